@@ -0,0 +1,313 @@
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::task::JoinSet;
+
+use crate::connection::{
+    check_length, Limits, RequestParser, CRLF, READ_CHUNK_SIZE, WRITE_CHUNK_SIZE,
+};
+use crate::error::HttpError;
+use crate::listener::{Listener, SocketFile};
+use crate::server::Server;
+use crate::shutdown::{self, Shutdown};
+use crate::{respond, Body, Cli, Request, Response, UNKNOWN_HTTP_VERSION};
+
+/// Serves connections as tasks on a tokio event loop rather than tying up a worker
+/// thread each, so idle keep-alive connections and slow streams cost next to nothing.
+///
+/// Requests go through the same `Server`, so routes, handlers and middleware work
+/// unchanged. Handlers are still blocking code, so they're run with `block_in_place`
+/// to keep them from stalling other connections on the same runtime thread.
+pub fn serve(
+    listeners: Vec<Listener>,
+    server: Server,
+    config: Cli,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .enable_all()
+        .build()?;
+    let server = Arc::new(server);
+    let config = Arc::new(config);
+
+    runtime.block_on(async move {
+        let mut accept_loops = JoinSet::new();
+        for listener in listeners {
+            listener.set_nonblocking(true)?;
+            let server = Arc::clone(&server);
+            let config = Arc::clone(&config);
+            let shutdown = shutdown.clone();
+            match listener {
+                Listener::Tcp(listener) => {
+                    let listener = TcpListener::from_std(listener)?;
+                    accept_loops.spawn(accept_loop(listener, None, server, config, shutdown));
+                }
+                Listener::Unix(listener, socket_file) => {
+                    let listener = UnixListener::from_std(listener)?;
+                    accept_loops.spawn(accept_loop(
+                        listener,
+                        socket_file,
+                        server,
+                        config,
+                        shutdown,
+                    ));
+                }
+            }
+        }
+
+        while !shutdown.is_draining() {
+            tokio::time::sleep(shutdown::POLL_INTERVAL).await;
+        }
+        println!(
+            "Shutting down, waiting up to {}s for open connections to finish.",
+            config.drain_timeout
+        );
+        Shutdown::exit_after(Duration::from_secs(config.drain_timeout));
+        // Each loop only returns once its own connections have closed.
+        while accept_loops.join_next().await.is_some() {}
+        println!("All connections closed, exiting.");
+        Ok(())
+    })
+}
+
+/// Takes connections off one listener until a shutdown, then waits for them to finish.
+async fn accept_loop(
+    listener: impl Accept,
+    socket_file: Option<SocketFile>,
+    server: Arc<Server>,
+    config: Arc<Cli>,
+    shutdown: Shutdown,
+) {
+    let mut connections = JoinSet::new();
+
+    while !shutdown.is_draining() {
+        // Wakes up every so often to check for a shutdown.
+        let accepted = match tokio::time::timeout(shutdown::POLL_INTERVAL, listener.accept()).await
+        {
+            Ok(accepted) => accepted,
+            Err(_) => continue,
+        };
+        match accepted {
+            Ok(stream) => {
+                println!("accepted new connection: {:?}", stream);
+                let server = Arc::clone(&server);
+                let config = Arc::clone(&config);
+                let shutdown = shutdown.clone();
+                connections.spawn(async move {
+                    handle_connection(stream, &server, &config, &shutdown).await
+                });
+            }
+            Err(e) => {
+                println!("error: {}", e);
+            }
+        }
+        // Keeps finished connections from piling up in the set.
+        while connections.try_join_next().is_some() {}
+    }
+
+    drop(listener);
+    drop(socket_file);
+    while connections.join_next().await.is_some() {}
+}
+
+/// The tokio listeners, which don't share a trait of their own.
+trait Accept: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug + 'static;
+
+    async fn accept(&self) -> std::io::Result<Self::Stream>;
+}
+
+impl Accept for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self) -> std::io::Result<Self::Stream> {
+        TcpListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+impl Accept for UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self) -> std::io::Result<Self::Stream> {
+        UnixListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    server: &Server,
+    config: &Cli,
+    shutdown: &Shutdown,
+) {
+    let mut connection = AsyncConnection::new(stream, Limits::from(config));
+
+    loop {
+        let mut request = match connection.read_request().await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(err) => {
+                // There's no telling where the next request would start, so the
+                // connection can't be reused after this.
+                eprintln!("Failed to read request: {}", err);
+                let response = err.into_response();
+                if let Err(err) =
+                    write_response(response, &mut connection, false, UNKNOWN_HTTP_VERSION).await
+                {
+                    eprintln!("{}", err);
+                }
+                break;
+            }
+        };
+        connection.requests_served += 1;
+        let response = tokio::task::block_in_place(|| respond(server, &mut request, config));
+        let keep_alive = request.keep_alive()
+            && connection.requests_served < connection.limits.max_requests
+            && !shutdown.is_draining()
+            && !response.ends_with_close(&request.http_version);
+
+        if let Err(err) =
+            write_response(response, &mut connection, keep_alive, &request.http_version).await
+        {
+            eprintln!("{}", err);
+            break;
+        }
+
+        if !keep_alive {
+            break;
+        }
+    }
+}
+
+/// The async counterpart to `Response::write_to_stream`.
+async fn write_response(
+    mut response: Response,
+    connection: &mut AsyncConnection<impl AsyncRead + AsyncWrite + Unpin>,
+    keep_alive: bool,
+    http_version: &str,
+) -> Result<usize> {
+    let (stream_output, chunked) = response.serialize_head(
+        keep_alive,
+        http_version,
+        connection.requests_served,
+        &connection.limits,
+    )?;
+
+    let length = response.content_length();
+    let mut sent = stream_output.len();
+    let write_result = match response.content {
+        Some(body @ (Body::File(_) | Body::Stream(_))) => {
+            match connection.stream.write_all(&stream_output).await {
+                Ok(()) => connection
+                    .copy_from(body.into_reader(), chunked, length)
+                    .await
+                    .map(|copied| sent += copied as usize),
+                Err(err) => Err(err),
+            }
+        }
+        _ => connection.stream.write_all(&stream_output).await,
+    };
+    match write_result {
+        Ok(()) => {
+            println!("Sent {sent} bytes back.");
+            Ok(sent)
+        }
+        Err(err) => Err(anyhow!("Could not write response to stream: {}", err)),
+    }
+}
+
+/// The async counterpart to `Connection`, keeping surplus bytes between requests in
+/// the same way.
+struct AsyncConnection<S> {
+    stream: S,
+    buffer: BytesMut,
+    requests_served: usize,
+    limits: Limits,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncConnection<S> {
+    fn new(stream: S, limits: Limits) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::new(),
+            requests_served: 0,
+            limits,
+        }
+    }
+
+    /// The async counterpart to `Connection::read_request`.
+    async fn read_request(&mut self) -> Result<Option<Request>, HttpError> {
+        let mut parser = RequestParser::new(self.limits);
+        loop {
+            if let Some(request) = parser.parse(&mut self.buffer)? {
+                return Ok(Some(request));
+            }
+            match self.fill_buffer().await {
+                Ok(0) => return parser.stopped(&self.buffer, None),
+                Ok(_) => {}
+                Err(err) => return parser.stopped(&self.buffer, Some(err)),
+            }
+        }
+    }
+
+    /// Copies a blocking reader onto the connection, optionally as chunks. Unless it's
+    /// chunked, a body with a known `length` is cut off there, and fails if it's short.
+    async fn copy_from(
+        &mut self,
+        reader: Box<dyn Read + Send>,
+        chunked: bool,
+        length: Option<u64>,
+    ) -> std::io::Result<u64> {
+        let length = length.filter(|_| !chunked);
+        let mut reader = reader.take(length.unwrap_or(u64::MAX));
+        let mut chunk = vec![0; WRITE_CHUNK_SIZE];
+        let mut written = 0;
+
+        loop {
+            let read_bytes = match tokio::task::block_in_place(|| reader.read(&mut chunk)) {
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if read_bytes == 0 {
+                break;
+            }
+
+            if chunked {
+                let size_line = format!("{:X}\r\n", read_bytes);
+                self.stream.write_all(size_line.as_bytes()).await?;
+                self.stream.write_all(&chunk[..read_bytes]).await?;
+                self.stream.write_all(CRLF).await?;
+                written += (size_line.len() + read_bytes + CRLF.len()) as u64;
+            } else {
+                self.stream.write_all(&chunk[..read_bytes]).await?;
+                written += read_bytes as u64;
+            }
+        }
+
+        if chunked {
+            self.stream.write_all(b"0\r\n\r\n").await?;
+            written += 5;
+        }
+        if let Some(length) = length {
+            check_length(written, length)?;
+        }
+        Ok(written)
+    }
+
+    async fn fill_buffer(&mut self) -> std::io::Result<usize> {
+        self.buffer.reserve(READ_CHUNK_SIZE);
+        let read = self.stream.read_buf(&mut self.buffer);
+        let returned_bytes = match tokio::time::timeout(self.limits.timeout, read).await {
+            Ok(result) => result?,
+            Err(_) => return Err(ErrorKind::TimedOut.into()),
+        };
+        println!("Bytes returned: {}", returned_bytes);
+        Ok(returned_bytes)
+    }
+}
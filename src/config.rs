@@ -0,0 +1,316 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::roots::{self, Root};
+use crate::{listener, Cli, QueueFullPolicy};
+
+/// The settings a `--config` file can hold, every one of them optional.
+///
+/// ```toml
+/// [listeners]
+/// bind = ["0.0.0.0", "[::]:8080"]
+/// port = 4221
+/// unix_socket = "/run/http-server.sock"
+/// unix_socket_mode = "660"
+///
+/// [timeouts]
+/// idle = 5
+/// drain = 30
+///
+/// [limits]
+/// max_header_size = 8192
+/// max_body_size = 67108864
+/// max_requests_per_connection = 100
+/// workers = 16
+/// queue_size = 64
+/// queue_full = "reject"
+///
+/// [files]
+/// directory = "/srv/uploads"
+/// read_only = false
+/// list_directories = true
+///
+/// [[files.roots]]
+/// name = "docs"
+/// path = "/srv/docs"
+/// read_only = true
+///
+/// [compression]
+/// gzip = true
+///
+/// [logging]
+/// requests = true
+///
+/// [routes]
+/// disabled = ["/user-agent"]
+/// ```
+///
+/// YAML files take the same layout.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    listeners: Listeners,
+    timeouts: Timeouts,
+    limits: Limits,
+    files: Files,
+    compression: Compression,
+    logging: Logging,
+    routes: Routes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Listeners {
+    bind: Option<Vec<String>>,
+    port: Option<u16>,
+    unix_socket: Option<PathBuf>,
+    /// In octal, as a string so it isn't read as decimal.
+    unix_socket_mode: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Timeouts {
+    idle: Option<u64>,
+    drain: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Limits {
+    max_header_size: Option<usize>,
+    max_body_size: Option<usize>,
+    max_requests_per_connection: Option<usize>,
+    workers: Option<usize>,
+    queue_size: Option<usize>,
+    queue_full: Option<QueueFullPolicy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Files {
+    directory: Option<PathBuf>,
+    read_only: Option<bool>,
+    list_directories: Option<bool>,
+    roots: Option<Vec<Root>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Compression {
+    gzip: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Logging {
+    requests: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Routes {
+    disabled: Option<Vec<String>>,
+}
+
+impl FileConfig {
+    /// Reads a config file, TOML or YAML depending on its extension.
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        match extension {
+            "toml" => toml::from_str(&text).with_context(|| format!("In {}", path.display())),
+            "yaml" | "yml" => {
+                serde_yaml::from_str(&text).with_context(|| format!("In {}", path.display()))
+            }
+            _ => Err(anyhow!(
+                "Can't tell what format {} is in, expected a .toml, .yaml or .yml file",
+                path.display()
+            )),
+        }
+    }
+
+    /// Fills in `config` from the file, except for whatever was given on the command
+    /// line.
+    pub fn apply(self, config: &mut Cli, matches: &ArgMatches) -> Result<()> {
+        // Arguments are named after their `Cli` field.
+        let from_file = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        macro_rules! merge {
+            ($field:ident, $value:expr) => {
+                if let Some(value) = $value {
+                    if from_file(stringify!($field)) {
+                        config.$field = value;
+                    }
+                }
+            };
+        }
+
+        let unix_socket_mode = self
+            .listeners
+            .unix_socket_mode
+            .map(|mode| listener::parse_mode(&mode).map_err(|err| anyhow!(err)))
+            .transpose()?;
+
+        merge!(bind, self.listeners.bind);
+        merge!(port, self.listeners.port);
+        merge!(unix_socket, self.listeners.unix_socket.map(Some));
+        merge!(unix_socket_mode, unix_socket_mode);
+        merge!(timeout, self.timeouts.idle);
+        merge!(drain_timeout, self.timeouts.drain);
+        merge!(max_header_size, self.limits.max_header_size);
+        merge!(max_body_size, self.limits.max_body_size);
+        merge!(
+            max_requests_per_connection,
+            self.limits.max_requests_per_connection
+        );
+        merge!(workers, self.limits.workers);
+        merge!(queue_size, self.limits.queue_size);
+        merge!(queue_full, self.limits.queue_full);
+        merge!(directory, self.files.directory.map(Some));
+        merge!(read_only, self.files.read_only);
+        merge!(list_directories, self.files.list_directories);
+        merge!(root, self.files.roots);
+        merge!(no_compression, self.compression.gzip.map(|gzip| !gzip));
+        merge!(no_request_log, self.logging.requests.map(|log| !log));
+        merge!(disable_route, self.routes.disabled);
+        Ok(())
+    }
+}
+
+/// Merges in the `--config` file, if there is one, and checks the settings make sense.
+pub fn load(config: &mut Cli, matches: &ArgMatches) -> Result<()> {
+    if let Some(path) = config.config.clone() {
+        FileConfig::read(&path)?.apply(config, matches)?;
+    }
+
+    config.roots = roots::build(config)?;
+    if config.workers == 0 {
+        return Err(anyhow!("Expecting at least one worker"));
+    }
+    if config.max_requests_per_connection == 0 {
+        return Err(anyhow!(
+            "Expecting connections to allow at least one request"
+        ));
+    }
+    if config.timeout == 0 {
+        return Err(anyhow!("Expecting a timeout of at least a second"));
+    }
+    listener::addresses(config)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    /// Applies `file` on top of the command line `args`.
+    fn merged(file: FileConfig, args: &[&str]) -> Cli {
+        let matches =
+            Cli::command().get_matches_from(std::iter::once("test").chain(args.iter().copied()));
+        let mut config = Cli::from_arg_matches(&matches).unwrap();
+        file.apply(&mut config, &matches).unwrap();
+        config
+    }
+
+    const TOML: &str = r#"
+        [listeners]
+        port = 8080
+        unix_socket_mode = "600"
+
+        [timeouts]
+        idle = 9
+
+        [limits]
+        workers = 3
+        queue_full = "drop"
+
+        [compression]
+        gzip = false
+
+        [routes]
+        disabled = ["/user-agent"]
+    "#;
+
+    const YAML: &str = "
+listeners:
+  port: 8080
+  unix_socket_mode: '600'
+timeouts:
+  idle: 9
+limits:
+  workers: 3
+  queue_full: drop
+compression:
+  gzip: false
+routes:
+  disabled: [/user-agent]
+";
+
+    #[test]
+    fn file_settings_replace_defaults() {
+        for file in [
+            toml::from_str::<FileConfig>(TOML).unwrap(),
+            serde_yaml::from_str::<FileConfig>(YAML).unwrap(),
+        ] {
+            let config = merged(file, &[]);
+            assert_eq!(config.port, 8080);
+            assert_eq!(config.unix_socket_mode, 0o600);
+            assert_eq!(config.timeout, 9);
+            assert_eq!(config.workers, 3);
+            assert!(matches!(config.queue_full, QueueFullPolicy::Drop));
+            assert!(config.no_compression);
+            assert_eq!(config.disable_route, ["/user-agent"]);
+            // Whatever the file leaves out keeps its default.
+            assert_eq!(config.max_header_size, crate::DEFAULT_MAX_HEADER_SIZE);
+        }
+    }
+
+    #[test]
+    fn command_line_wins_over_the_file() {
+        for file in [
+            toml::from_str::<FileConfig>(TOML).unwrap(),
+            serde_yaml::from_str::<FileConfig>(YAML).unwrap(),
+        ] {
+            let config = merged(
+                file,
+                &[
+                    "--port",
+                    "9000",
+                    "--timeout",
+                    "2",
+                    "--queue-full",
+                    "reject",
+                    "--disable-route",
+                    "/echo/{word}",
+                    "--unix-socket-mode",
+                    "660",
+                ],
+            );
+            assert_eq!(config.port, 9000);
+            assert_eq!(config.timeout, 2);
+            assert!(matches!(config.queue_full, QueueFullPolicy::Reject));
+            assert_eq!(config.disable_route, ["/echo/{word}"]);
+            assert_eq!(config.unix_socket_mode, 0o660);
+            // Not given on the command line, so still from the file.
+            assert_eq!(config.workers, 3);
+            assert!(config.no_compression);
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_settings() {
+        assert!(toml::from_str::<FileConfig>("[limits]\nworker = 3").is_err());
+        assert!(toml::from_str::<FileConfig>("[limit]\nworkers = 3").is_err());
+        assert!(serde_yaml::from_str::<FileConfig>("limits:\n  workers: -1").is_err());
+    }
+}
@@ -0,0 +1,575 @@
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::error::HttpError;
+use crate::{is_token, BodyFraming, Cli, Request, END_OF_HEADER};
+
+pub(crate) const READ_CHUNK_SIZE: usize = 1024; // bytes
+pub(crate) const WRITE_CHUNK_SIZE: usize = 8192; // bytes
+pub(crate) const CRLF: &[u8] = b"\r\n";
+
+/// How much a client may send, and how long it may take about it.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Also the keep-alive idle timeout between requests.
+    pub timeout: Duration,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    pub max_requests: usize,
+}
+
+impl From<&Cli> for Limits {
+    fn from(config: &Cli) -> Self {
+        Self {
+            timeout: Duration::from_secs(config.timeout),
+            max_header_size: config.max_header_size,
+            max_body_size: config.max_body_size,
+            max_requests: config.max_requests_per_connection,
+        }
+    }
+}
+
+/// A client connection that may carry several requests, one after another.
+///
+/// Reads go through a buffer that lives as long as the connection, so when a client
+/// pipelines requests in one write, whatever follows the current request is kept for
+/// the next call instead of being dropped. Requests are read, handled and answered one
+/// at a time, so pipelined requests are always responded to in the order they arrived.
+///
+/// Any stream will do, the read timeout is left to whoever opened it.
+pub struct Connection<S> {
+    stream: S,
+    buffer: BytesMut,
+    pub requests_served: usize,
+    pub limits: Limits,
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub fn new(stream: S, limits: Limits) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::new(),
+            requests_served: 0,
+            limits,
+        }
+    }
+
+    /// Reads the next request off the connection.
+    ///
+    /// Returns `Ok(None)` when the client has closed the connection, or has left it
+    /// idle for longer than the read timeout, before sending anything.
+    pub fn read_request(&mut self) -> Result<Option<Request>, HttpError> {
+        let mut parser = RequestParser::new(self.limits);
+        loop {
+            if let Some(request) = parser.parse(&mut self.buffer)? {
+                return Ok(Some(request));
+            }
+            match self.fill_buffer() {
+                Ok(0) => return parser.stopped(&self.buffer, None),
+                Ok(_) => {}
+                Err(err) => return parser.stopped(&self.buffer, Some(err)),
+            }
+        }
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(data)
+    }
+
+    /// Copies everything left in `reader` onto the connection.
+    pub fn copy_from(&mut self, reader: &mut impl Read) -> std::io::Result<u64> {
+        std::io::copy(reader, &mut self.stream)
+    }
+
+    /// Copies exactly `length` bytes from `reader` onto the connection, failing if it
+    /// runs out first.
+    pub fn copy_exactly(&mut self, reader: &mut impl Read, length: u64) -> std::io::Result<u64> {
+        let copied = std::io::copy(&mut Read::take(reader, length), &mut self.stream)?;
+        check_length(copied, length)?;
+        Ok(copied)
+    }
+
+    /// Copies everything left in `reader` onto the connection using chunked transfer
+    /// encoding, returning the number of bytes written.
+    pub fn write_chunked_from(&mut self, reader: &mut impl Read) -> std::io::Result<u64> {
+        let mut chunk = [0; WRITE_CHUNK_SIZE];
+        let mut written = 0;
+
+        loop {
+            let read_bytes = match reader.read(&mut chunk) {
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if read_bytes == 0 {
+                break;
+            }
+
+            let size_line = format!("{:X}\r\n", read_bytes);
+            self.stream.write_all(size_line.as_bytes())?;
+            self.stream.write_all(&chunk[..read_bytes])?;
+            self.stream.write_all(CRLF)?;
+            written += (size_line.len() + read_bytes + CRLF.len()) as u64;
+        }
+
+        self.stream.write_all(b"0\r\n\r\n")?;
+        Ok(written + 5)
+    }
+
+    fn fill_buffer(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let returned_bytes = self.stream.read(&mut chunk)?;
+        println!("Bytes returned: {}", returned_bytes);
+
+        self.buffer.extend_from_slice(&chunk[..returned_bytes]);
+        Ok(returned_bytes)
+    }
+}
+
+/// Works through a request as its bytes arrive. It never reads anything itself, each
+/// call takes what it can off the front of the buffer and returns `Ok(None)` when it
+/// needs more, so the blocking and async connections share the same HTTP parsing.
+pub(crate) struct RequestParser {
+    limits: Limits,
+    /// Filled in once the whole head has arrived.
+    head: Option<(Request, BodyDecoder)>,
+}
+
+/// How the rest of the body is to be read.
+enum BodyDecoder {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+impl RequestParser {
+    pub fn new(limits: Limits) -> Self {
+        Self { limits, head: None }
+    }
+
+    /// Returns the request once all of it is in `buffer`, leaving anything after it
+    /// (a pipelined request, say) where it is.
+    pub fn parse(&mut self, buffer: &mut BytesMut) -> Result<Option<Request>, HttpError> {
+        if self.head.is_none() {
+            let Some(head) = take_until(buffer, END_OF_HEADER, self.limits.max_header_size)? else {
+                return Ok(None);
+            };
+            let (request, framing) = Request::parse_head(&head)?;
+            let body = match framing {
+                BodyFraming::None => return Ok(Some(request)),
+                BodyFraming::Length(length) if length > self.limits.max_body_size => {
+                    return Err(HttpError::PayloadTooLarge(self.limits.max_body_size))
+                }
+                BodyFraming::Length(length) => BodyDecoder::Length(length),
+                BodyFraming::Chunked => BodyDecoder::Chunked(ChunkedDecoder::new(self.limits)),
+            };
+            self.head = Some((request, body));
+        }
+
+        let Some((request, body)) = &mut self.head else {
+            unreachable!("The head was parsed above.");
+        };
+        match body {
+            BodyDecoder::Length(length) => {
+                if buffer.len() < *length {
+                    return Ok(None);
+                }
+                request.body = Some(buffer.split_to(*length).freeze());
+            }
+            BodyDecoder::Chunked(decoder) => match decoder.decode(buffer)? {
+                Some((body, trailers)) => request.set_chunked_body(body, trailers),
+                None => return Ok(None),
+            },
+        }
+        Ok(self.head.take().map(|(request, _)| request))
+    }
+
+    /// Makes sense of the connection running dry, either closed by the client (no
+    /// `error`) or a read failing. Between requests that just means the client is done
+    /// with the connection, partway through one it's an error.
+    pub fn stopped(
+        &self,
+        buffer: &BytesMut,
+        error: Option<std::io::Error>,
+    ) -> Result<Option<Request>, HttpError> {
+        let between_requests = self.head.is_none() && buffer.is_empty();
+        match error {
+            None if between_requests => {
+                println!("Connection closed by client.");
+                Ok(None)
+            }
+            Some(err)
+                if between_requests
+                    && matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                println!("Connection idle for too long, closing.");
+                Ok(None)
+            }
+            Some(err) => Err(read_error(err)),
+            None if self.head.is_none() => Err(HttpError::BadRequest(format!(
+                "Connection closed before the end of the request header, data recieved: {}.",
+                buffer.escape_ascii()
+            ))),
+            None => Err(HttpError::BadRequest(
+                "Connection closed partway through the request body.".into(),
+            )),
+        }
+    }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, along with any trailer fields sent
+/// after the last chunk.
+///
+/// Chunk extensions (`;name=value` after the size) are accepted and ignored.
+pub(crate) struct ChunkedDecoder {
+    limits: Limits,
+    body: BytesMut,
+    trailers: Trailers,
+    /// All the trailer lines together, held to the same limit as the head.
+    trailer_size: usize,
+    state: ChunkState,
+}
+
+/// Fields sent after a chunked body, in the order they arrived.
+type Trailers = Vec<(String, String)>;
+
+#[derive(Clone, Copy)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    /// The CRLF after a chunk's data.
+    DataEnd(usize),
+    Trailers,
+}
+
+impl ChunkedDecoder {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            body: BytesMut::new(),
+            trailers: Vec::new(),
+            trailer_size: 0,
+            state: ChunkState::Size,
+        }
+    }
+
+    /// Returns the body and trailers once the whole of it is in `buffer`.
+    pub fn decode(
+        &mut self,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<(Bytes, Trailers)>, HttpError> {
+        let max_body_size = self.limits.max_body_size;
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let Some(line) = take_line(buffer, &self.limits)? else {
+                        return Ok(None);
+                    };
+                    let size = parse_chunk_size(&line, max_body_size)?;
+                    // The size comes from the client, so it can be anywhere up to
+                    // `usize::MAX`.
+                    if size > max_body_size - self.body.len() {
+                        return Err(HttpError::PayloadTooLarge(max_body_size));
+                    }
+                    self.state = match size {
+                        0 => ChunkState::Trailers,
+                        size => ChunkState::Data(size),
+                    };
+                }
+                ChunkState::Data(size) => {
+                    if buffer.len() < size {
+                        return Ok(None);
+                    }
+                    self.body.extend_from_slice(&buffer.split_to(size));
+                    self.state = ChunkState::DataEnd(size);
+                }
+                ChunkState::DataEnd(size) => {
+                    let Some(line) = take_line(buffer, &self.limits)? else {
+                        return Ok(None);
+                    };
+                    if !line.is_empty() {
+                        return Err(HttpError::BadRequest(format!(
+                            "Chunk data was longer than its size of {}.",
+                            size
+                        )));
+                    }
+                    self.state = ChunkState::Size;
+                }
+                // The last chunk is followed by optional trailer fields and an empty line.
+                ChunkState::Trailers => {
+                    let Some(line) = take_line(buffer, &self.limits)? else {
+                        return Ok(None);
+                    };
+                    self.trailer_size += line.len() + CRLF.len();
+                    if self.trailer_size > self.limits.max_header_size {
+                        return Err(HttpError::HeaderTooLarge(self.limits.max_header_size));
+                    }
+                    if line.is_empty() {
+                        let body = std::mem::take(&mut self.body).freeze();
+                        return Ok(Some((body, std::mem::take(&mut self.trailers))));
+                    }
+                    self.trailers.push(parse_trailer(&line)?);
+                }
+            }
+        }
+    }
+}
+
+/// Takes everything up to `delimiter` off the front of `buffer`, consuming the
+/// delimiter as well, failing if it isn't found within `max_length` bytes.
+fn take_until(
+    buffer: &mut BytesMut,
+    delimiter: &[u8],
+    max_length: usize,
+) -> Result<Option<Bytes>, HttpError> {
+    match find(buffer, delimiter) {
+        Some(position) if position > max_length => Err(HttpError::HeaderTooLarge(max_length)),
+        Some(position) => {
+            let found = buffer.split_to(position).freeze();
+            buffer.advance(delimiter.len());
+            Ok(Some(found))
+        }
+        None if buffer.len() > max_length + delimiter.len() => {
+            Err(HttpError::HeaderTooLarge(max_length))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Takes the next CRLF terminated line, without the CRLF.
+fn take_line(buffer: &mut BytesMut, limits: &Limits) -> Result<Option<Bytes>, HttpError> {
+    take_until(buffer, CRLF, limits.max_header_size)
+}
+
+fn find(buffer: &[u8], delimiter: &[u8]) -> Option<usize> {
+    buffer
+        .windows(delimiter.len())
+        .position(|window| window == delimiter)
+}
+
+/// Parses the line before each chunk, the size in hex optionally followed by chunk
+/// extensions (`;name=value`) which are ignored.
+fn parse_chunk_size(size_line: &[u8], max_body_size: usize) -> Result<usize, HttpError> {
+    let size_line = std::str::from_utf8(size_line).map_err(|err| {
+        HttpError::BadRequest(format!("Chunk size line is not valid UTF-8: {}", err))
+    })?;
+    let size_str = size_line
+        .split_once(';')
+        .map_or(size_line, |(size, _extensions)| size)
+        .trim();
+
+    if size_str.is_empty() || !size_str.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(HttpError::BadRequest(format!(
+            "Malformed chunk size `{}`.",
+            size_line
+        )));
+    }
+    usize::from_str_radix(size_str, 16).map_err(|_| HttpError::PayloadTooLarge(max_body_size))
+}
+
+fn parse_trailer(line: &[u8]) -> Result<(String, String), HttpError> {
+    let line = std::str::from_utf8(line)
+        .map_err(|err| HttpError::BadRequest(format!("Trailer is not valid UTF-8: {}", err)))?;
+    match line.split_once(':') {
+        Some((key, _)) if !is_token(key) => Err(HttpError::BadRequest(format!(
+            "Invalid trailer field name `{}`.",
+            key
+        ))),
+        Some((key, value)) => Ok((key.into(), value.trim().into())),
+        None => Err(HttpError::BadRequest(format!(
+            "Failed to parse trailer, got: {}.",
+            line
+        ))),
+    }
+}
+
+/// Fails when a body came up short of the length the client was told to expect, as
+/// there's no way of making up the difference without breaking the connection.
+pub(crate) fn check_length(copied: u64, length: u64) -> std::io::Result<()> {
+    if copied < length {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("Body ended after {} of its {} bytes", copied, length),
+        ));
+    }
+    Ok(())
+}
+
+/// A read failing partway through a request means the client stopped sending.
+pub(crate) fn read_error(err: std::io::Error) -> HttpError {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => HttpError::Timeout,
+        _ => HttpError::Io(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_body_size: usize) -> Limits {
+        Limits {
+            timeout: Duration::from_secs(1),
+            max_header_size: 128,
+            max_body_size,
+            max_requests: 1,
+        }
+    }
+
+    fn decode(input: &str, max_body_size: usize) -> Result<Option<(Bytes, Trailers)>, HttpError> {
+        ChunkedDecoder::new(limits(max_body_size)).decode(&mut BytesMut::from(input))
+    }
+
+    #[test]
+    fn decodes_chunks_extensions_and_trailers() {
+        let mut buffer =
+            BytesMut::from("3;name=value\r\nabc\r\nA\r\n0123456789\r\n0\r\nX-Sum: 1\r\n\r\nrest");
+        let (body, trailers) = ChunkedDecoder::new(limits(100))
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, "abc0123456789");
+        assert_eq!(trailers, [("X-Sum".to_string(), "1".to_string())]);
+        // Whatever comes after the body is left for the next request.
+        assert_eq!(buffer, "rest");
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_the_body() {
+        let input = b"3\r\nabc\r\n0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::new(limits(100));
+        let mut buffer = BytesMut::new();
+        for (i, byte) in input.iter().enumerate() {
+            buffer.extend_from_slice(&[*byte]);
+            let decoded = decoder.decode(&mut buffer).unwrap();
+            assert_eq!(decoded.is_some(), i == input.len() - 1);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        for input in [
+            "\r\n",
+            " \r\n",
+            "xyz\r\n",
+            "-1\r\n",
+            "+1\r\n",
+            "0x1\r\n",
+            ";ext\r\n",
+            "3\r\nabcd\r\n",
+            "0\r\nno colon\r\n\r\n",
+            "0\r\nX-Sum : 1\r\n\r\n",
+        ] {
+            assert!(
+                matches!(decode(input, 100), Err(HttpError::BadRequest(_))),
+                "{:?} wasn't refused",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_and_overflowing_chunks() {
+        for input in [
+            "5\r\n",
+            "3\r\nabc\r\n2\r\n",
+            "1\r\na\r\nFFFFFFFFFFFFFFFF\r\n",
+            "1FFFFFFFFFFFFFFFF\r\n",
+        ] {
+            assert!(
+                matches!(decode(input, 4), Err(HttpError::PayloadTooLarge(4))),
+                "{:?} wasn't refused",
+                input
+            );
+        }
+        assert!(matches!(decode("30\r\n", 100), Ok(None)));
+    }
+
+    #[test]
+    fn rejects_long_size_lines() {
+        let input = format!("1;{}\r\n", "x".repeat(200));
+        assert!(matches!(
+            decode(&input, 100),
+            Err(HttpError::HeaderTooLarge(128))
+        ));
+    }
+
+    #[test]
+    fn limits_the_size_of_all_trailers_together() {
+        let trailer = "X-Padding: 12345678901234567890\r\n";
+        assert!(trailer.len() < 128);
+        assert!(matches!(
+            decode(&format!("0\r\n{}\r\n", trailer), 100),
+            Ok(Some(_))
+        ));
+        assert!(matches!(
+            decode(&format!("0\r\n{}\r\n", trailer.repeat(4)), 100),
+            Err(HttpError::HeaderTooLarge(128))
+        ));
+    }
+
+    #[test]
+    fn parses_pipelined_requests() {
+        let mut buffer = BytesMut::from(
+            "POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /b HTTP/1.1\r\n\r\n",
+        );
+        let mut parser = RequestParser::new(limits(100));
+        let first = parser.parse(&mut buffer).unwrap().unwrap();
+        assert_eq!(first.path, "/a");
+        assert_eq!(first.body.unwrap(), "hi");
+        let second = RequestParser::new(limits(100))
+            .parse(&mut buffer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.path, "/b");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn refuses_ambiguous_framing() {
+        for input in [
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding : chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\n Transfer-Encoding: chunked\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            assert!(
+                matches!(
+                    RequestParser::new(limits(100)).parse(&mut BytesMut::from(input)),
+                    Err(HttpError::BadRequest(_))
+                ),
+                "{:?} wasn't refused",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn only_accepts_plain_chunked() {
+        for coding in [
+            "gzip",
+            "gzip, chunked",
+            "chunked, chunked",
+            "gzip\r\nTransfer-Encoding: chunked",
+        ] {
+            let input = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n", coding);
+            assert!(
+                matches!(
+                    RequestParser::new(limits(100)).parse(&mut BytesMut::from(input.as_str())),
+                    Err(HttpError::NotImplemented(_))
+                ),
+                "{:?} wasn't refused",
+                coding
+            );
+        }
+        let mut buffer =
+            BytesMut::from("POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n0\r\n\r\n");
+        let request = RequestParser::new(limits(100))
+            .parse(&mut buffer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body.unwrap(), "");
+    }
+}
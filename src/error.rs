@@ -0,0 +1,67 @@
+use thiserror::Error;
+
+use crate::{HttpCode, Response, CONTENT_TYPE_HEADER};
+
+/// Anything that stops a request from being handled normally, each mapping onto the
+/// status the client gets back.
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Request header is larger than the {0} byte limit.")]
+    HeaderTooLarge(usize),
+    #[error("Request body is larger than the {0} byte limit.")]
+    PayloadTooLarge(usize),
+    #[error("{0}")]
+    NotImplemented(String),
+    #[error("Timed out waiting for the rest of the request.")]
+    Timeout,
+    #[error("Connection failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl HttpError {
+    pub fn http_code(&self) -> HttpCode {
+        match self {
+            HttpError::BadRequest(_) => HttpCode::BadRequest,
+            HttpError::Forbidden(_) => HttpCode::Forbidden,
+            HttpError::NotFound(_) => HttpCode::NotFound,
+            HttpError::Conflict(_) => HttpCode::Conflict,
+            HttpError::HeaderTooLarge(_) => HttpCode::RequestHeaderFieldsTooLarge,
+            HttpError::PayloadTooLarge(_) => HttpCode::ContentTooLarge,
+            HttpError::NotImplemented(_) => HttpCode::NotImplemented,
+            HttpError::Timeout => HttpCode::RequestTimeout,
+            HttpError::Io(_) | HttpError::Internal(_) => HttpCode::InternalServerError,
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        let http_code = self.http_code();
+        // Internal details are for the logs, not the client.
+        let message = match &self {
+            HttpError::Io(_) | HttpError::Internal(_) => {
+                eprintln!("CRITICAL: {}", self);
+                "Internal server error.".to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        let mut response = Response {
+            http_code,
+            content: Some(message.into()),
+            ..Default::default()
+        };
+        response
+            .headers
+            .insert(CONTENT_TYPE_HEADER.into(), "text/plain".into());
+        response
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::error::HttpError;
+use crate::listing;
+use crate::range::{self, Multipart, Ranges};
+use crate::roots;
+use crate::router::Router;
+use crate::validators::{self, Validators};
+use crate::{
+    open_file, Body, Cli, HttpCode, HttpMethod, Request, Response, CONTENT_LENGTH_HEADER,
+    CONTENT_TYPE_HEADER,
+};
+
+/// Every route the server answers.
+pub fn routes() -> Router {
+    let mut router = Router::new();
+    router.add(HttpMethod::Get, "/", root);
+    router.add(HttpMethod::Get, "/echo/{word}", echo);
+    router.add(HttpMethod::Get, "/user-agent", user_agent);
+    router.add(HttpMethod::Get, "/files/{*path}", get_file);
+    router.add(HttpMethod::Post, "/files/{*path}", post_file);
+    router.add(HttpMethod::Put, "/files/{*path}", put_file);
+    router.add(HttpMethod::Delete, "/files/{*path}", delete_file);
+    router
+}
+
+/// Held from checking a write's preconditions until it's done, so two clients can't
+/// both pass `If-Match` for the same version and have one of them silently lose.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+fn root(_request: &Request, _config: &Cli) -> Result<Response, HttpError> {
+    Ok(Response::default())
+}
+
+fn echo(request: &Request, _config: &Cli) -> Result<Response, HttpError> {
+    let mut response = Response::default();
+    let echo_word = request.var("word").unwrap_or_default();
+
+    response
+        .headers
+        .insert("Content-Type".into(), "text/plain".into());
+    response
+        .headers
+        .insert("Content-Length".into(), format!("{}", echo_word.len()));
+
+    response.content = Some(echo_word.as_bytes().into());
+    Ok(response)
+}
+
+fn user_agent(request: &Request, _config: &Cli) -> Result<Response, HttpError> {
+    let mut response = Response::default();
+    let user_agent = request
+        .headers
+        .get("User-Agent")
+        .ok_or_else(|| HttpError::BadRequest("Missing User-Agent header.".into()))?;
+
+    response
+        .headers
+        .insert("Content-Type".into(), "text/plain".into());
+    response
+        .headers
+        .insert("Content-Length".into(), format!("{}", user_agent.len()));
+
+    response.content = Some(user_agent.as_bytes().into());
+    Ok(response)
+}
+
+fn get_file(request: &Request, config: &Cli) -> Result<Response, HttpError> {
+    let file = roots::locate(config, request.var("path").unwrap_or_default())?;
+    if file.path.is_dir() {
+        return get_directory(request, config, &file);
+    }
+    serve_file(request, &file.path, "application/octet-stream")
+}
+
+/// Serves a directory's `index.html` if it has one, otherwise a listing of it if
+/// they're turned on.
+fn get_directory(
+    request: &Request,
+    config: &Cli,
+    dir: &roots::Located,
+) -> Result<Response, HttpError> {
+    let mut response = Response::default();
+    let (url_path, query) = request
+        .path
+        .split_once('?')
+        .map_or((request.path.as_str(), None), |(path, query)| {
+            (path, Some(query))
+        });
+
+    // Relative links, from the listing or the index page, need the trailing slash to
+    // resolve inside the directory rather than next to it.
+    if !url_path.ends_with('/') {
+        let location = match query {
+            Some(query) => format!("{}/?{}", url_path, query),
+            None => format!("{}/", url_path),
+        };
+        response.http_code = HttpCode::MovedPermanently;
+        response.headers.insert("Location".into(), location);
+        return Ok(response);
+    }
+
+    let index = roots::locate(
+        config,
+        &format!("{}/index.html", request.var("path").unwrap_or_default()),
+    )?;
+    if index.path.is_file() {
+        return serve_file(request, &index.path, "text/html; charset=utf-8");
+    }
+    if !config.list_directories {
+        return Err(HttpError::Forbidden(format!(
+            "{} is a directory, and listing them is turned off.",
+            url_path
+        )));
+    }
+    listing::list(request, dir, url_path)
+}
+
+/// Sends a file, or the ranges of it asked for, unless the client's copy is current.
+fn serve_file(request: &Request, path: &Path, content_type: &str) -> Result<Response, HttpError> {
+    let mut response = Response::default();
+    let Ok((mut file, metadata)) = open_file(path) else {
+        response.http_code = HttpCode::NotFound;
+        return Ok(response);
+    };
+    let length = metadata.len();
+    let validators = Validators::of(&metadata);
+    response
+        .headers
+        .insert("Accept-Ranges".into(), "bytes".into());
+    response
+        .headers
+        .insert("ETag".into(), validators.etag.clone());
+    response
+        .headers
+        .insert("Last-Modified".into(), validators.last_modified_header());
+
+    if let Some(http_code) = validators::preconditions(request, Some(&validators)) {
+        response.http_code = http_code;
+        return Ok(response);
+    }
+    response
+        .headers
+        .insert("Content-Type".into(), content_type.into());
+
+    // Ranges of a different version of the file would be no use to the client, so a
+    // stale `If-Range` gets the whole thing.
+    let ranges = match request.headers.get("Range") {
+        Some(range)
+            if request
+                .headers
+                .get("If-Range")
+                .map_or(true, |if_range| validators.if_range_matches(if_range)) =>
+        {
+            range::parse(range, length)
+        }
+        _ => Ranges::Ignored,
+    };
+
+    match ranges {
+        Ranges::Ignored => {
+            response
+                .headers
+                .insert("Content-Length".into(), format!("{}", length));
+            response.content = Some(Body::File(file));
+        }
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            file.seek(SeekFrom::Start(range.start))?;
+            response.http_code = HttpCode::PartialContent;
+            response
+                .headers
+                .insert("Content-Range".into(), range.content_range(length));
+            response
+                .headers
+                .insert("Content-Length".into(), range.len().to_string());
+            response.content = Some(Body::Stream(Box::new(file.take(range.len()))));
+        }
+        Ranges::Satisfiable(ranges) => {
+            let boundary = random_token();
+            let (body, body_length) =
+                Multipart::new(file, &ranges, length, content_type, &boundary);
+            response.http_code = HttpCode::PartialContent;
+            response.headers.insert(
+                "Content-Type".into(),
+                format!("multipart/byteranges; boundary={}", boundary),
+            );
+            response
+                .headers
+                .insert("Content-Length".into(), body_length.to_string());
+            response.content = Some(Body::Stream(Box::new(body)));
+        }
+        Ranges::Unsatisfiable => {
+            response.http_code = HttpCode::RangeNotSatisfiable;
+            response
+                .headers
+                .insert("Content-Range".into(), format!("bytes */{}", length));
+            response
+                .headers
+                .insert("Content-Type".into(), "text/plain".into());
+            response.content = Some(format!("File is only {} bytes long.", length).into());
+        }
+    }
+    Ok(response)
+}
+
+fn post_file(request: &Request, config: &Cli) -> Result<Response, HttpError> {
+    let mut response = Response::default();
+    let file_name = request.var("path").unwrap_or_default();
+
+    if let Some(content_type) = request.headers.get(CONTENT_TYPE_HEADER) {
+        if content_type != "application/octet-stream" {
+            response.http_code = HttpCode::BadRequest;
+            let response_msg = format!(
+                "Unsupported content type `{}` expected `application/octet-stream`",
+                content_type
+            );
+            response
+                .headers
+                .insert(CONTENT_LENGTH_HEADER.into(), response_msg.len().to_string());
+            response.content = Some(response_msg.into());
+            return Ok(response);
+        }
+    } else {
+        response.http_code = HttpCode::BadRequest;
+        let response_msg = "Expected content type header but got nothing.";
+        response
+            .headers
+            .insert(CONTENT_LENGTH_HEADER.into(), response_msg.len().to_string());
+        response.content = Some(response_msg.into());
+        return Ok(response);
+    }
+
+    if let Some(content_length) = request.headers.get(CONTENT_LENGTH_HEADER) {
+        debug_assert_eq!(
+            content_length.parse::<usize>().unwrap(),
+            request.body.to_owned().unwrap().len()
+        );
+    } else {
+        response.http_code = HttpCode::BadRequest;
+        let response_msg = format!("Missing {} header", CONTENT_LENGTH_HEADER);
+        response
+            .headers
+            .insert(CONTENT_LENGTH_HEADER.into(), response_msg.len().to_string());
+        response.content = Some(response_msg.into());
+        return Ok(response);
+    }
+
+    let file = roots::locate(config, file_name)?;
+    if file.root.read_only {
+        response.http_code = HttpCode::Forbidden;
+        response.content = Some("Uploads aren't allowed here.".into());
+        return Ok(response);
+    }
+
+    if file.name.is_empty() {
+        response.http_code = HttpCode::BadRequest;
+        response.content = Some(
+            "No file name sent in url, url should be formatted like /files/<file_name>".into(),
+        );
+        return Ok(response);
+    }
+
+    let current = current_validators(&file.path);
+    if let Some(http_code) = validators::preconditions(request, current.as_ref()) {
+        response.http_code = http_code;
+        return Ok(response);
+    }
+
+    let parent = file.path.parent().unwrap_or(&file.root.path);
+    if !parent.is_dir() {
+        return Err(HttpError::Conflict(format!(
+            "The directory for {} doesn't exist.",
+            file.name
+        )));
+    }
+
+    let file_name = &file.name;
+    match File::create_new(&file.path) {
+        Ok(mut file) => {
+            match file.write_all(request.body.as_ref().expect("No file data to upload.")) {
+                Ok(_) => {
+                    response.http_code = HttpCode::Created;
+                }
+                Err(err) => {
+                    eprintln!("Failed to load file to {}, got error: {}", file_name, err);
+                    response.http_code = HttpCode::InternalServerError;
+                }
+            }
+        }
+        Err(err) => match err.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                response.http_code = HttpCode::BadRequest;
+                let response_msg = format!("File {} already exists.", file_name);
+                response
+                    .headers
+                    .insert(CONTENT_LENGTH_HEADER.into(), response_msg.len().to_string());
+                response.content = Some(response_msg.into());
+            }
+            _ => {
+                eprintln!(
+                    "CRITICAL: Could upload a user's file due to an internal server error: {}",
+                    err
+                );
+                response.http_code = HttpCode::InternalServerError;
+            }
+        },
+    }
+    Ok(response)
+}
+
+/// Creates or replaces a file.
+///
+/// The body is written to a temporary file next to it which is then renamed over the
+/// top, so anyone reading the file sees either all of the old one or all of the new.
+fn put_file(request: &Request, config: &Cli) -> Result<Response, HttpError> {
+    let mut response = Response::default();
+    let file = writable_file(request, config)?;
+
+    let parent = file.path.parent().unwrap_or(&file.root.path);
+    if !parent.is_dir() {
+        return Err(HttpError::Conflict(format!(
+            "The directory for {} doesn't exist.",
+            file.name
+        )));
+    }
+    if file.path.is_dir() {
+        return Err(HttpError::Conflict(format!(
+            "{} is a directory.",
+            file.name
+        )));
+    }
+
+    let file_name = file.path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = parent.join(format!(".{}.{}.tmp", file_name, random_token()));
+    let written = File::create_new(&temp_path).and_then(|mut temp| {
+        temp.write_all(request.body.as_deref().unwrap_or_default())?;
+        temp.sync_all()
+    });
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+
+    let guard = WRITE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let current = current_validators(&file.path);
+    if let Some(http_code) = validators::preconditions(request, current.as_ref()) {
+        drop(guard);
+        let _ = std::fs::remove_file(&temp_path);
+        response.http_code = http_code;
+        return Ok(response);
+    }
+    if let Err(err) = std::fs::rename(&temp_path, &file.path) {
+        drop(guard);
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+    drop(guard);
+
+    response.http_code = match current {
+        Some(_) => HttpCode::NoContent,
+        None => HttpCode::Created,
+    };
+    if let Some(validators) = current_validators(&file.path) {
+        response.headers.insert("ETag".into(), validators.etag);
+    }
+    Ok(response)
+}
+
+fn delete_file(request: &Request, config: &Cli) -> Result<Response, HttpError> {
+    let mut response = Response::default();
+    let file = writable_file(request, config)?;
+
+    if file.path.is_dir() {
+        return Err(HttpError::Conflict(format!(
+            "{} is a directory.",
+            file.name
+        )));
+    }
+
+    let _guard = WRITE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let current = current_validators(&file.path)
+        .ok_or_else(|| HttpError::NotFound(format!("{} doesn't exist.", file.name)))?;
+    if let Some(http_code) = validators::preconditions(request, Some(&current)) {
+        response.http_code = http_code;
+        return Ok(response);
+    }
+    std::fs::remove_file(&file.path)?;
+
+    response.http_code = HttpCode::NoContent;
+    Ok(response)
+}
+
+/// The file a PUT or DELETE is for, as long as it's named and can be changed.
+fn writable_file<'a>(request: &Request, config: &'a Cli) -> Result<roots::Located<'a>, HttpError> {
+    let file = roots::locate(config, request.var("path").unwrap_or_default())?;
+    if file.root.read_only {
+        return Err(HttpError::Forbidden("Changes aren't allowed here.".into()));
+    }
+    if file.name.is_empty() {
+        return Err(HttpError::BadRequest(
+            "No file name sent in url, url should be formatted like /files/<file_name>".into(),
+        ));
+    }
+    Ok(file)
+}
+
+/// The validators of the regular file at `path`, if there is one.
+fn current_validators(path: &Path) -> Option<Validators> {
+    std::fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| Validators::of(&metadata))
+}
+
+/// Random enough to keep temporary file names and multipart boundaries from clashing.
+fn random_token() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
@@ -0,0 +1,96 @@
+/// HTTP header fields for a request or response.
+///
+/// Header names are case-insensitive, so lookups ignore case, but each field keeps
+/// the name exactly as it was given so it's written back out the same way. A name can
+/// appear more than once (`Set-Cookie`, or `Accept-Encoding` split over several lines)
+/// and fields are kept in the order they were added.
+#[derive(Debug, Default, Clone)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of the first field called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The values of every field called `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The comma separated elements of every field called `name`, for list headers like
+    /// `Accept-Encoding` or `Connection` which may be spread over several fields.
+    pub fn get_list<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(|element| element.trim())
+            .filter(|element| !element.is_empty())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets `name` to `value`, replacing any fields already called `name`.
+    pub fn insert(&mut self, name: String, value: String) {
+        self.remove(&name);
+        self.fields.push((name, value));
+    }
+
+    /// Adds another field called `name`, keeping any that are already there.
+    pub fn append(&mut self, name: String, value: String) {
+        self.fields.push((name, value));
+    }
+
+    /// Adds `element` to the comma separated list in `name`, unless it's already in
+    /// there, so headers like `Vary` can be built up by more than one handler.
+    pub fn add_to_list(&mut self, name: &str, element: &str) {
+        if self
+            .get_list(name)
+            .any(|v| v == "*" || v.eq_ignore_ascii_case(element))
+        {
+            return;
+        }
+        match self
+            .fields
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            Some((_, value)) => *value = format!("{}, {}", value, element),
+            None => self.fields.push((name.into(), element.into())),
+        }
+    }
+
+    /// Removes every field called `name`, returning the first value.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.fields.retain_mut(|(key, value)| {
+            if !key.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if removed.is_none() {
+                removed = Some(std::mem::take(value));
+            }
+            false
+        });
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
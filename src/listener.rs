@@ -0,0 +1,260 @@
+use std::fs::Permissions;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::{Cli, DEFAULT_BIND_ADDRESS};
+
+/// The first file descriptor passed on by socket activation, after stdin, stdout and
+/// stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Somewhere connections come in from.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Along with the socket's file, which is removed once the listener is done with,
+    /// unless the socket was handed to us by whatever started the server.
+    Unix(UnixListener, #[allow(dead_code)] Option<SocketFile>),
+}
+
+impl Listener {
+    pub fn accept(&self) -> std::io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Self::Unix(listener, _) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Self::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+/// A client connection from any of the listeners.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Removes a Unix socket's file when dropped, so the next start doesn't find it lying
+/// around.
+#[derive(Debug)]
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            eprintln!("Could not remove socket {}: {}", self.0.display(), err);
+        }
+    }
+}
+
+/// Every listener asked for on the command line, along with any passed on by socket
+/// activation.
+pub fn bind(config: &Cli) -> Result<Vec<Listener>> {
+    let mut listeners = inherited()?;
+    let addresses = if listeners.is_empty() {
+        addresses(config)?
+    } else {
+        // Inherited sockets take the place of the default address.
+        config
+            .bind
+            .iter()
+            .map(|bind| parse_address(bind, config.port))
+            .collect::<Result<_>>()?
+    };
+    listeners.extend(bind_all(&addresses)?.into_iter().map(Listener::Tcp));
+    if let Some(path) = &config.unix_socket {
+        listeners.push(bind_unix(path, config.unix_socket_mode)?);
+    }
+    Ok(listeners)
+}
+
+/// Every address given with `--bind`, using `--port` for the ones that don't name a
+/// port of their own.
+///
+/// Addresses can be IPv4 or IPv6, with IPv6 ones in brackets when they come with a
+/// port, e.g. `0.0.0.0`, `::1`, `127.0.0.1:8080` or `[::]:8080`. With no `--bind` the
+/// server listens on localhost, unless it's been given a Unix socket instead.
+pub fn addresses(config: &Cli) -> Result<Vec<SocketAddr>> {
+    if config.bind.is_empty() && config.unix_socket.is_none() {
+        return Ok(vec![parse_address(DEFAULT_BIND_ADDRESS, config.port)?]);
+    }
+    config
+        .bind
+        .iter()
+        .map(|bind| parse_address(bind, config.port))
+        .collect()
+}
+
+fn parse_address(bind: &str, default_port: u16) -> Result<SocketAddr> {
+    if let Ok(address) = bind.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    let ip = bind
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(bind);
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+        Err(_) => Err(anyhow!(
+            "Could not parse bind address `{}`, expected an IP address optionally followed by a port",
+            bind
+        )),
+    }
+}
+
+/// Binds a listener for each address, failing if any of them can't be bound.
+pub fn bind_all(addresses: &[SocketAddr]) -> Result<Vec<TcpListener>> {
+    addresses
+        .iter()
+        .map(|address| {
+            let listener = TcpListener::bind(address)
+                .map_err(|err| anyhow!("Could not bind to {}: {}", address, err))?;
+            println!("Listening on {}", address);
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Binds a Unix socket at `path` and gives it `mode` permissions.
+///
+/// A socket left behind by a server that didn't shut down cleanly is removed first,
+/// but not one that something is still listening on, or a file that isn't a socket.
+pub fn bind_unix(path: &Path, mode: u32) -> Result<Listener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!(
+                "{} already exists and isn't a socket",
+                path.display()
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "{} is already in use by another server",
+                path.display()
+            ));
+        }
+        println!("Removing stale socket {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|err| anyhow!("Could not bind to {}: {}", path.display(), err))?;
+    let socket_file = Some(SocketFile(path.into()));
+    std::fs::set_permissions(path, Permissions::from_mode(mode))
+        .map_err(|err| anyhow!("Could not set permissions on {}: {}", path.display(), err))?;
+    println!("Listening on {} with mode {:o}", path.display(), mode);
+    Ok(Listener::Unix(listener, socket_file))
+}
+
+/// Listening sockets opened by a supervisor and passed on to the server, following
+/// systemd's socket activation convention.
+///
+/// `LISTEN_FDS` says how many there are, starting at file descriptor 3, and
+/// `LISTEN_PID` who they're for, so they aren't picked up by a process that only
+/// inherited the variables. Both are cleared afterwards for the same reason.
+pub fn inherited() -> Result<Vec<Listener>> {
+    let (Ok(pid), Ok(fds)) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) else {
+        return Ok(Vec::new());
+    };
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        println!("Ignoring sockets passed on to process {}", pid);
+        return Ok(Vec::new());
+    }
+    let end = fds
+        .parse::<RawFd>()
+        .ok()
+        .filter(|fds| *fds >= 0)
+        .and_then(|fds| LISTEN_FDS_START.checked_add(fds))
+        .ok_or_else(|| anyhow!("LISTEN_FDS should be a number of sockets, got `{}`", fds))?;
+
+    (LISTEN_FDS_START..end)
+        .map(|fd| {
+            // SAFETY: LISTEN_PID and LISTEN_FDS are taken at their word that these
+            // descriptors are open and were passed on for this process to own, and
+            // nothing else in it has touched them yet. Below only checks that they're
+            // sockets, not that they're listening, which would just fail on accept.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // There's no telling what kind of socket it is from the outside, but a
+            // Unix socket has no IP address.
+            match listener.local_addr() {
+                Ok(address) => {
+                    println!("Listening on inherited socket {}", address);
+                    Ok(Listener::Tcp(listener))
+                }
+                Err(_) => {
+                    let listener = unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) };
+                    let address = listener.local_addr().map_err(|err| {
+                        anyhow!("Inherited file descriptor {} isn't a socket: {}", fd, err)
+                    })?;
+                    println!("Listening on inherited socket {:?}", address);
+                    Ok(Listener::Unix(listener, None))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parses file permissions written in octal, like `chmod` takes them.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("`{}` isn't an octal file mode like 660", mode)),
+    }
+}
@@ -0,0 +1,429 @@
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::error::HttpError;
+use crate::roots::{self, Located};
+use crate::{Request, Response, CONTENT_TYPE_HEADER};
+
+/// One file or directory in a listing.
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+#[derive(Serialize)]
+struct JsonListing<'a> {
+    path: &'a str,
+    entries: Vec<JsonEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Left out for directories, where it doesn't mean much.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Seconds since the Unix epoch.
+    modified: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortBy {
+    Name,
+    Size,
+    Modified,
+}
+
+/// Lists the directory `dir`, which the client asked for as `url_path`.
+///
+/// The listing is HTML or JSON depending on which the `Accept` header prefers, and can
+/// be ordered with `?sort=name|size|modified&order=asc|desc` and narrowed down to
+/// names containing `?filter=`. Hidden files are left out, as are entries that can't
+/// be read and symlinks leading out of the root. Directories always come before files.
+pub fn list(request: &Request, dir: &Located, url_path: &str) -> Result<Response, HttpError> {
+    let sort_by = match request.query("sort").as_deref() {
+        None | Some("name") => SortBy::Name,
+        Some("size") => SortBy::Size,
+        Some("modified") => SortBy::Modified,
+        Some(other) => {
+            return Err(HttpError::BadRequest(format!(
+                "Can't sort by `{}`, expected name, size or modified.",
+                other
+            )))
+        }
+    };
+    let descending = match request.query("order").as_deref() {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(other) => {
+            return Err(HttpError::BadRequest(format!(
+                "Unknown order `{}`, expected asc or desc.",
+                other
+            )))
+        }
+    };
+    let filter = request.query("filter").map(|filter| filter.to_lowercase());
+
+    let mut entries = Vec::new();
+    for dir_entry in std::fs::read_dir(&dir.path)? {
+        let Ok(dir_entry) = dir_entry else { continue };
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        if let Some(filter) = &filter {
+            if !name.to_lowercase().contains(filter) {
+                continue;
+            }
+        }
+        // Symlinks are listed as whatever they point to, as long as that's somewhere
+        // the client could actually get to.
+        let is_symlink = dir_entry.file_type().is_ok_and(|kind| kind.is_symlink());
+        if is_symlink && roots::confine(&dir.root.path, &dir_entry.path()).is_err() {
+            continue;
+        }
+        let Ok(metadata) = std::fs::metadata(dir_entry.path()) else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        });
+    }
+
+    entries.sort_by(|a, b| {
+        let ordering = match sort_by {
+            SortBy::Name => a.name.cmp(&b.name),
+            SortBy::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortBy::Modified => a
+                .modified
+                .cmp(&b.modified)
+                .then_with(|| a.name.cmp(&b.name)),
+        };
+        let ordering = if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        match (a.is_dir, b.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => ordering,
+        }
+    });
+
+    let mut response = Response::default();
+    if prefers_json(request) {
+        let listing = JsonListing {
+            path: url_path,
+            entries: entries
+                .iter()
+                .map(|entry| JsonEntry {
+                    name: &entry.name,
+                    kind: if entry.is_dir { "directory" } else { "file" },
+                    size: (!entry.is_dir).then_some(entry.size),
+                    modified: seconds_since_epoch(entry.modified),
+                })
+                .collect(),
+        };
+        let json = serde_json::to_vec(&listing)
+            .map_err(|err| HttpError::Internal(format!("Could not write listing: {}", err)))?;
+        response
+            .headers
+            .insert(CONTENT_TYPE_HEADER.into(), "application/json".into());
+        response.content = Some(json.into());
+    } else {
+        response.headers.insert(
+            CONTENT_TYPE_HEADER.into(),
+            "text/html; charset=utf-8".into(),
+        );
+        response.content = Some(html(&entries, url_path, sort_by, descending).into());
+    }
+    // Whichever one was picked depended on the request.
+    response.headers.add_to_list("Vary", "Accept");
+    Ok(response)
+}
+
+fn html(entries: &[Entry], url_path: &str, sort_by: SortBy, descending: bool) -> String {
+    let title = escape_html(url_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {0}</title>\n</head>\n<body>\n<h1>Index of {0}</h1>\n<table>\n<tr>",
+        title
+    );
+    // Each heading sorts by its column, flipping the order if it's already sorted by it.
+    for (column, label) in [
+        (SortBy::Name, "Name"),
+        (SortBy::Size, "Size"),
+        (SortBy::Modified, "Modified"),
+    ] {
+        let order = if column == sort_by && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        let sort = match column {
+            SortBy::Name => "name",
+            SortBy::Size => "size",
+            SortBy::Modified => "modified",
+        };
+        html.push_str(&format!(
+            "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>",
+            sort, order, label
+        ));
+    }
+    html.push_str("</tr>\n");
+
+    if url_path.trim_end_matches('/') != "/files" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            percent_encode(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            httpdate::fmt_http_date(entry.modified)
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Whether the `Accept` header ranks JSON above HTML, going by the quality of the most
+/// specific media range matching each.
+fn prefers_json(request: &Request) -> bool {
+    let quality = |media_type: &str| {
+        let (main_type, _) = media_type.split_once('/').unwrap_or_default();
+        let mut best: Option<(u8, f32)> = None;
+        for range in request.headers.get_list("Accept") {
+            let mut params = range.split(';').map(str::trim);
+            let range_type = params.next().unwrap_or_default();
+            let specificity = if range_type.eq_ignore_ascii_case(media_type) {
+                2
+            } else if range_type.eq_ignore_ascii_case(&format!("{}/*", main_type)) {
+                1
+            } else if range_type == "*/*" {
+                0
+            } else {
+                continue;
+            };
+            let q = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if best.map_or(true, |(best, _)| specificity > best) {
+                best = Some((specificity, q));
+            }
+        }
+        best.map_or(0.0, |(_, q)| q)
+    };
+    quality("application/json") > quality("text/html")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Encodes a file name for use as a relative link, everything but unreserved
+/// characters is escaped so it can't be read as a path, query or scheme.
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{Body, Cli};
+
+    fn request(query: &str, accept: &str) -> Request {
+        let head = format!("GET /files/?{} HTTP/1.1\r\nAccept: {}", query, accept);
+        Request::parse_head(head.as_bytes()).unwrap().0
+    }
+
+    /// A root with a directory, files of different sizes and ages, a hidden file, and
+    /// symlinks pointing inside it, outside it and nowhere.
+    fn setup(test: &str) -> (PathBuf, Cli) {
+        let base = std::env::temp_dir().join(format!(
+            "http-server-listing-{}-{}",
+            test,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(root.join("Big.txt"), "0123456789").unwrap();
+        std::fs::write(root.join("small.txt"), "abc").unwrap();
+        std::fs::write(root.join(".hidden"), "").unwrap();
+        std::fs::write(base.join("outside/secret.txt"), "secret").unwrap();
+        File::options()
+            .write(true)
+            .open(root.join("small.txt"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000))
+            .unwrap();
+        symlink(root.join("small.txt"), root.join("link.txt")).unwrap();
+        symlink(base.join("outside/secret.txt"), root.join("escape.txt")).unwrap();
+        symlink(base.join("outside"), root.join("escape")).unwrap();
+        symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        let mut config = Cli::parse_from(["test", "--directory", root.to_str().unwrap()]);
+        config.roots = roots::build(&config).unwrap();
+        (base, config)
+    }
+
+    fn names(config: &Cli, query: &str) -> Vec<String> {
+        let dir = roots::locate(config, "").unwrap();
+        let response = list(&request(query, "application/json"), &dir, "/files/").unwrap();
+        let Some(Body::Bytes(json)) = response.content else {
+            panic!("No listing for {}", query);
+        };
+        let listing: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        listing["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sorts_with_directories_first() {
+        let (base, config) = setup("sort");
+        assert_eq!(
+            names(&config, ""),
+            ["sub", "Big.txt", "link.txt", "small.txt"]
+        );
+        assert_eq!(
+            names(&config, "order=desc"),
+            ["sub", "small.txt", "link.txt", "Big.txt"]
+        );
+        assert_eq!(
+            names(&config, "sort=size"),
+            ["sub", "link.txt", "small.txt", "Big.txt"]
+        );
+        assert_eq!(
+            names(&config, "sort=modified&order=desc"),
+            ["sub", "Big.txt", "small.txt", "link.txt"]
+        );
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn filters_by_name_ignoring_case() {
+        let (base, config) = setup("filter");
+        assert_eq!(names(&config, "filter=BIG"), ["Big.txt"]);
+        assert_eq!(names(&config, "filter=s+t"), Vec::<String>::new());
+        assert_eq!(
+            names(&config, "filter=%2Etxt&sort=size"),
+            ["link.txt", "small.txt", "Big.txt"]
+        );
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn refuses_unknown_sorting() {
+        let (base, config) = setup("unknown");
+        let dir = roots::locate(&config, "").unwrap();
+        for query in ["sort=date", "order=up", "sort="] {
+            assert!(
+                matches!(
+                    list(&request(query, "*/*"), &dir, "/files/"),
+                    Err(HttpError::BadRequest(_))
+                ),
+                "{} wasn't refused",
+                query
+            );
+        }
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn leaves_out_symlinks_out_of_the_root() {
+        let (base, config) = setup("symlinks");
+        let listed = names(&config, "");
+        assert!(listed.contains(&"link.txt".to_string()));
+        for hidden in ["escape.txt", "escape", "dangling", ".hidden"] {
+            assert!(
+                !listed.contains(&hidden.to_string()),
+                "{} was listed",
+                hidden
+            );
+        }
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn picks_json_by_quality_and_specificity() {
+        let json = |accept| prefers_json(&request("", accept));
+        assert!(json("application/json"));
+        assert!(json("text/html;q=0.5, application/json"));
+        assert!(json("text/*;q=0.2, application/*;q=0.3"));
+        // The most specific range wins, even when a broader one has a higher q.
+        assert!(json("application/json;q=0.9, */*;q=1, text/html;q=0.1"));
+        assert!(!json("application/json;q=0.1, */*;q=1"));
+        assert!(!json("application/json;q=0.5, text/html"));
+        assert!(!json("application/json, text/html"));
+        assert!(!json("*/*"));
+        assert!(!json("text/plain"));
+        assert!(!json(""));
+    }
+
+    #[test]
+    fn escapes_names() {
+        assert_eq!(
+            escape_html(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+        assert_eq!(percent_encode("a b/c?d#e%.txt"), "a%20b%2Fc%3Fd%23e%25.txt");
+        assert_eq!(percent_encode("✓"), "%E2%9C%93");
+    }
+}
@@ -1,27 +1,152 @@
-use clap::Parser;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
 use core::panic;
-use std::collections::{HashMap};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{IoSlice, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write};
 use std::time::Duration;
-use std::fs;
 
 use anyhow::{anyhow, Result};
-
-const DEFAULT_TIMEOUT: u8 = 5; // seconds
-const END_OF_HEADER: &str = "\r\n\r\n";
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::connection::{Connection, Limits};
+use crate::error::HttpError;
+use crate::headers::Headers;
+use crate::listener::Stream;
+use crate::pool::WorkerPool;
+use crate::server::Server;
+use crate::shutdown::Shutdown;
+use crate::status::HttpCode;
+
+#[cfg(feature = "async")]
+mod async_mode;
+mod config;
+mod connection;
+mod error;
+mod handlers;
+mod headers;
+mod listener;
+mod listing;
+mod middleware;
+mod pool;
+mod range;
+mod roots;
+mod router;
+mod server;
+mod shutdown;
+mod status;
+mod validators;
+
+const DEFAULT_TIMEOUT: u64 = 5; // seconds
+const END_OF_HEADER: &[u8] = b"\r\n\r\n";
+/// What to assume of a client whose request line hasn't been read, the oldest version
+/// we answer so nothing is sent that it mightn't understand.
+const UNKNOWN_HTTP_VERSION: &str = "HTTP/1.0";
 const CONTENT_TYPE_HEADER: &str = "Content-Type";
 const CONTENT_ENCODING_HEADER: &str = "Content-Encoding";
 const CONTENT_LENGTH_HEADER: &str = "Content-Length";
+const CONNECTION_HEADER: &str = "Connection";
+const TRANSFER_ENCODING_HEADER: &str = "Transfer-Encoding";
+const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+const DEFAULT_MAX_HEADER_SIZE: usize = 8 * 1024; // bytes
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024; // bytes
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 4221;
+const DEFAULT_UNIX_SOCKET_MODE: &str = "660";
 const DEFAULT_FILES_DIR: &str = "/tmp/rust-http-server/";
+const DEFAULT_WORKERS: usize = 16;
+const DEFAULT_QUEUE_SIZE: usize = 64;
+const RETRY_AFTER: u8 = 1; // seconds
+const DEFAULT_DRAIN_TIMEOUT: u64 = 30; // seconds
 
+/// Settings can also come from a `--config` file, anything given on the command line
+/// takes priority over it.
 #[derive(Parser)]
 struct Cli {
+    /// TOML or YAML file to read settings from, see `config::FileConfig`.
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+    /// Check the settings and exit without starting the server.
+    #[arg(long)]
+    check_config: bool,
+    /// The default directory for `/files` [default: /tmp/rust-http-server/]
     #[arg(long)]
     directory: Option<std::path::PathBuf>,
+    /// Another directory for `/files`, as NAME=PATH, served under `/files/NAME/`. Can be
+    /// given more than once.
+    #[arg(long, value_parser = roots::parse_root)]
+    root: Vec<roots::Root>,
+    /// Refuse uploads to every root.
+    #[arg(long)]
+    read_only: bool,
+    /// List the contents of directories under `/files` that have no `index.html`.
+    #[arg(long)]
+    list_directories: bool,
+    /// Every root `/files` serves, filled in from the above by `config::load`.
+    #[arg(skip)]
+    roots: Vec<roots::Root>,
+    /// Address to listen on, IPv4 or IPv6 and optionally with a port. Can be given
+    /// more than once to listen on several addresses [default: 127.0.0.1]
+    #[arg(long)]
+    bind: Vec<String>,
+    /// Port for any `--bind` address that doesn't give its own.
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    /// Also listen on a Unix socket at this path, or only on it if there's no `--bind`.
+    #[arg(long)]
+    unix_socket: Option<std::path::PathBuf>,
+    /// Permissions for the Unix socket, in octal.
+    #[arg(long, default_value = DEFAULT_UNIX_SOCKET_MODE, value_parser = listener::parse_mode)]
+    unix_socket_mode: u32,
+    /// Number of connections handled at once.
+    #[arg(long, default_value_t = DEFAULT_WORKERS)]
+    workers: usize,
+    /// Number of accepted connections that can wait for a free worker.
+    #[arg(long, default_value_t = DEFAULT_QUEUE_SIZE)]
+    queue_size: usize,
+    /// What to do with new connections when the queue is full.
+    #[arg(long, value_enum, default_value_t = QueueFullPolicy::Reject)]
+    queue_full: QueueFullPolicy,
+    /// Seconds to let in-flight requests finish after SIGTERM or SIGINT before exiting
+    /// regardless.
+    #[arg(long, default_value_t = DEFAULT_DRAIN_TIMEOUT)]
+    drain_timeout: u64,
+    /// Seconds a connection can sit idle, or take between reads, before it's closed.
+    #[arg(long, default_value_t = DEFAULT_TIMEOUT)]
+    timeout: u64,
+    /// Largest request line and headers accepted, in bytes.
+    #[arg(long, default_value_t = DEFAULT_MAX_HEADER_SIZE)]
+    max_header_size: usize,
+    /// Largest request body accepted, in bytes.
+    #[arg(long, default_value_t = DEFAULT_MAX_BODY_SIZE)]
+    max_body_size: usize,
+    /// Requests served on one connection before it's closed.
+    #[arg(long, default_value_t = DEFAULT_MAX_REQUESTS_PER_CONNECTION)]
+    max_requests_per_connection: usize,
+    /// Don't gzip responses, even for clients that accept it.
+    #[arg(long)]
+    no_compression: bool,
+    /// Don't log each request.
+    #[arg(long)]
+    no_request_log: bool,
+    /// Turn off the route with this pattern, e.g. `/echo/{word}`. Can be given more
+    /// than once.
+    #[arg(long)]
+    disable_route: Vec<String>,
+    /// Serve connections on an async event loop instead of the worker pool, `--workers`
+    /// then sets the number of runtime threads.
+    #[cfg(feature = "async")]
+    #[arg(long = "async")]
+    async_mode: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum QueueFullPolicy {
+    /// Answer with 503 Service Unavailable and a Retry-After header.
+    Reject,
+    /// Close the connection without reading or writing anything.
+    Drop,
 }
 
 fn main() {
@@ -29,129 +154,268 @@ fn main() {
     println!("Logs from your program will appear here!");
 
     // Uncomment this block to pass the first stage
-    let mut config = Cli::parse();
-
-    match &config.directory {
-        None => config.directory = Some(DEFAULT_FILES_DIR.into()),
-        Some(dir) => {
-            if !dir.starts_with("/tmp/") {
-                panic!("Expecting server directory to be stored in /tmp/");
+    let matches = Cli::command().get_matches();
+    let mut config = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    let server = config::load(&mut config, &matches).and_then(|()| build_server(&config));
+    if config.check_config {
+        match server {
+            Ok(_) => println!("Configuration is valid."),
+            Err(err) => {
+                eprintln!("Invalid configuration: {:#}", err);
+                std::process::exit(1);
             }
         }
+        return;
+    }
+    let server = server.unwrap_or_else(|err| panic!("{:#}", err));
+
+    let listeners = listener::bind(&config).unwrap_or_else(|err| panic!("{}", err));
+    let shutdown = Shutdown::install().expect("Could not install signal handlers");
+
+    #[cfg(feature = "async")]
+    if config.async_mode {
+        return async_mode::serve(listeners, server, config, shutdown).unwrap();
     }
 
-    let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
+    // Accepting without blocking lets one loop take connections from every listener,
+    // and notice a shutdown in between.
+    for listener in &listeners {
+        listener
+            .set_nonblocking(true)
+            .expect("Could not make listener non-blocking");
+    }
 
     std::thread::scope(|scope| {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!("accepted new connection: {:?}", stream.peer_addr());
-                    scope.spawn(|| handle_connection(stream, &config));
-                }
-                Err(e) => {
-                    println!("error: {}", e);
+        let pool = WorkerPool::new(scope, config.workers, config.queue_size, |stream| {
+            handle_connection(stream, &server, &config, &shutdown)
+        });
+
+        while !shutdown.is_draining() {
+            let mut accepted_any = false;
+            for listener in &listeners {
+                match listener.accept() {
+                    Ok(stream) => {
+                        accepted_any = true;
+                        println!("accepted new connection: {:?}", stream);
+                        if let Err(err) = stream.set_nonblocking(false) {
+                            eprintln!("Could not set up connection: {}", err);
+                            continue;
+                        }
+                        if let Err(stream) = pool.submit(stream) {
+                            reject_connection(stream, &config);
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        println!("error: {}", e);
+                    }
                 }
             }
+            if !accepted_any {
+                std::thread::sleep(shutdown::POLL_INTERVAL);
+            }
         }
-    })
+
+        println!(
+            "Shutting down, waiting up to {}s for open connections to finish.",
+            config.drain_timeout
+        );
+        drop(listeners);
+        Shutdown::exit_after(Duration::from_secs(config.drain_timeout));
+        // Workers finish what's queued and then exit, which is what the scope waits on.
+        drop(pool);
+    });
+    println!("All connections closed, exiting.");
+}
+
+/// The router and middleware, leaving out whatever the settings turn off.
+fn build_server(config: &Cli) -> Result<Server> {
+    let mut router = handlers::routes();
+    for pattern in &config.disable_route {
+        if !router.remove(pattern) {
+            return Err(anyhow!(
+                "Can't disable `{}`, there's no such route",
+                pattern
+            ));
+        }
+    }
+
+    let mut server = Server::new(router);
+    if !config.no_request_log {
+        server.wrap(middleware::Logger);
+    }
+    if !config.no_compression {
+        server.wrap(middleware::Gzip);
+    }
+    Ok(server)
+}
+
+/// Turns away a connection that arrived while every worker was busy and the queue
+/// was full.
+fn reject_connection(stream: Stream, config: &Cli) {
+    eprintln!("Worker queue is full, turning away {:?}", stream);
+    if let QueueFullPolicy::Drop = config.queue_full {
+        return;
+    }
+
+    let mut response = Response {
+        http_code: HttpCode::ServiceUnavailable,
+        content: Some("Server is busy, try again shortly.".into()),
+        ..Default::default()
+    };
+    response
+        .headers
+        .insert("Retry-After".into(), RETRY_AFTER.to_string());
+
+    if let Err(err) = response.write_to_stream(
+        &mut Connection::new(stream, Limits::from(config)),
+        false,
+        UNKNOWN_HTTP_VERSION,
+    ) {
+        eprintln!("Could not send 503: {}", err);
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum HttpMethod {
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Trace,
+    Connect,
+    /// A well formed method this server doesn't know about.
+    Extension(String),
+}
+
+/// Whether `s` is a token, what methods and header field names are made of.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
 }
 
 impl HttpMethod {
     fn parse(method: &str) -> Result<Self> {
         match method {
             "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
             "POST" => Ok(Self::Post),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "PATCH" => Ok(Self::Patch),
+            "OPTIONS" => Ok(Self::Options),
+            "TRACE" => Ok(Self::Trace),
+            "CONNECT" => Ok(Self::Connect),
+            // Methods are tokens, anything else isn't HTTP.
+            _ if is_token(method) => Ok(Self::Extension(method.into())),
             _ => Err(anyhow!("Could not parse {} into HttpMethod", method)),
         }
     }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Patch => "PATCH",
+            Self::Options => "OPTIONS",
+            Self::Trace => "TRACE",
+            Self::Connect => "CONNECT",
+            Self::Extension(method) => method,
+        }
+    }
 }
 
 #[derive(Debug)]
-struct Request<'a> {
+struct Request {
     pub method: HttpMethod,
     pub path: String,
     pub http_version: String,
-    pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    pub headers: Headers,
+    pub body: Option<Bytes>,
 
-    vars: Option<HashMap<&'a str, &'a str>>
+    /// Path variables from the matched route, see `Router`.
+    vars: HashMap<String, String>,
 }
 
-/*
-
-I think that I can iterate through thet string until I get a buffer with `\r\n\r\n`
-which marks the end of the header. Then I need to pull in whatever is in Content-Length
-header.
-
-- Do post requests always have a Content-Length header?
-- Are headers always capitalised with dashes?
-
-*/
-
-impl Request<'_> {
-    fn from_stream(mut stream: &TcpStream) -> Result<Self> {
-        // 1KiB array
-        let mut buffer = [0; 1024];
-        let mut request = String::new();
-        let mut parsed_request: Request;
-        let mut returned_bytes: usize;
-
-        stream
-            .set_read_timeout(Some(Duration::from_secs(DEFAULT_TIMEOUT as u64)))
-            .unwrap();
-
-        /*
-        Could look to use `.as_ref()` on the stream.
-
-        Doing `.as_ref()` will consume the iterator but won't take ownership of it. So 
-        it would allow me to do `.lines()` to get the header here.
-        */
-        loop {
-            returned_bytes = stream.read(&mut buffer)?;
-            println!("Bytes returned: {}", returned_bytes);
+/// How the body following a request's header is delimited.
+enum BodyFraming {
+    None,
+    Length(usize),
+    Chunked,
+}
 
-            if returned_bytes == 0 {
-                break;
+impl Request {
+    /// Parses everything before the body, and works out how the body that follows is
+    /// framed.
+    fn parse_head(start_bytes: &[u8]) -> Result<(Self, BodyFraming), HttpError> {
+        let start_string = std::str::from_utf8(start_bytes).map_err(|err| {
+            HttpError::BadRequest(format!("Request header is not valid UTF-8: {}", err))
+        })?;
+        let parsed_request = Request::parse_up_to_header(start_string)
+            .map_err(|err| HttpError::BadRequest(err.to_string()))?;
+
+        if parsed_request
+            .headers
+            .contains_key(TRANSFER_ENCODING_HEADER)
+        {
+            // Letting both through is how request smuggling happens, so refuse to guess.
+            if parsed_request.headers.contains_key(CONTENT_LENGTH_HEADER) {
+                return Err(HttpError::BadRequest(format!(
+                    "Request has both {} and {} headers.",
+                    TRANSFER_ENCODING_HEADER, CONTENT_LENGTH_HEADER
+                )));
             }
-
-            request.push_str(std::str::from_utf8(&buffer[..returned_bytes]).unwrap());
-
-            if request.contains(END_OF_HEADER) {
-                println!("End of header found.");
-                break;
+            if !supports_chunked(&parsed_request.http_version) {
+                return Err(HttpError::BadRequest(format!(
+                    "{} isn't allowed in {} requests.",
+                    TRANSFER_ENCODING_HEADER, parsed_request.http_version
+                )));
             }
-        }
-
-        let start_string_length: usize;
-        // Get the string up to the end of the header.
-        if let Some((start_string, _)) = request.split_once(END_OF_HEADER) {
-            start_string_length = start_string.len();
-            parsed_request = Request::parse_up_to_header(&start_string)?;
-        } else {
-            return Err(anyhow!(
-                "Couldn't find end of header, data recieved: {}.",
-                request
-            ));
+            // Any other coding would have to be undone before the body is any use, and
+            // we don't, so only plain chunked will do.
+            let codings = parsed_request
+                .headers
+                .get_list(TRANSFER_ENCODING_HEADER)
+                .collect::<Vec<_>>();
+            if !matches!(codings[..], [coding] if coding.eq_ignore_ascii_case("chunked")) {
+                return Err(HttpError::NotImplemented(format!(
+                    "Unsupported {} `{}`, only chunked is supported.",
+                    TRANSFER_ENCODING_HEADER,
+                    codings.join(", ")
+                )));
+            }
+            return Ok((parsed_request, BodyFraming::Chunked));
         }
 
         let content_length: usize;
-        // Now that I have a header, if there is a content-length header, keep reading
-        // the stream until the data has been completely read in.
-        if let Some(content_header_value) = parsed_request.headers.get("Content-Length") {
+        // Without chunking, the body is exactly as long as Content-Length says, and
+        // without either header there isn't one. Reading it in is up to the caller.
+        if let Some(content_header_value) = parsed_request.headers.get(CONTENT_LENGTH_HEADER) {
+            // Repeats are only safe if every copy agrees on the length.
+            if parsed_request
+                .headers
+                .get_all(CONTENT_LENGTH_HEADER)
+                .any(|v| v != content_header_value)
+            {
+                return Err(HttpError::BadRequest(format!(
+                    "Request has conflicting {} headers.",
+                    CONTENT_LENGTH_HEADER
+                )));
+            }
             match content_header_value.parse::<usize>() {
                 Err(err) => {
-                    return Err(anyhow!(
+                    return Err(HttpError::BadRequest(format!(
                         "Could not parse Content-Length header value `{}` to number, got error: {}",
-                        content_header_value,
-                        err
-                    ))
+                        content_header_value, err
+                    )))
                 }
                 Ok(length) => {
                     content_length = length;
@@ -159,40 +423,67 @@ impl Request<'_> {
             }
         } else {
             eprintln!("No content length header set.");
-            return Ok(parsed_request);
+            return Ok((parsed_request, BodyFraming::None));
         }
 
-        while request.len() < (content_length + start_string_length + END_OF_HEADER.len()) {
-            returned_bytes = stream.read(&mut buffer)?;
-            println!("Bytes returned: {}", returned_bytes);
+        Ok((parsed_request, BodyFraming::Length(content_length)))
+    }
 
-            if returned_bytes == 0 {
-                break;
+    /// Fills in a body that was sent with chunked encoding.
+    fn set_chunked_body(&mut self, body: Bytes, trailers: Vec<(String, String)>) {
+        for (key, value) in trailers {
+            if !self.headers.contains_key(&key) {
+                self.headers.append(key, value);
             }
+        }
+        // From here on the request looks like it was sent with a Content-Length.
+        self.headers.remove(TRANSFER_ENCODING_HEADER);
+        self.headers
+            .insert(CONTENT_LENGTH_HEADER.into(), body.len().to_string());
+        self.body = Some(body);
+    }
 
-            request.push_str(std::str::from_utf8(&buffer[..returned_bytes]).unwrap());
+    /// The body as text, failing if it isn't valid UTF-8.
+    #[allow(dead_code)] // None of the current routes take a text body.
+    fn body_text(&self) -> Result<Option<&str>> {
+        match &self.body {
+            Some(body) => {
+                Ok(Some(std::str::from_utf8(body).map_err(|err| {
+                    anyhow!("Request body is not valid UTF-8: {}", err)
+                })?))
+            }
+            None => Ok(None),
         }
+    }
 
-        let (_, content) = request
-            .split_once(END_OF_HEADER)
-            .expect(format!("Could not find `{}` in string.", END_OF_HEADER).as_str());
+    /// The value of a path variable from the matched route.
+    fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|v| v.as_str())
+    }
 
-        if content_length == content.len() {
-            parsed_request.body = Some(content.into());
-            return Ok(parsed_request);
-        } else if content_length < content.len() {
-            return Err(anyhow!(
-                "More content data was sent, expected {} bytes but found {}",
-                content_length,
-                content.len()
-            ));
-        } else {
-            return Err(anyhow!(
-                "Not enough content data was sent, expected {} bytes but found {}",
-                content.len(),
-                content.len()
-            ));
+    /// The decoded value of the first query string parameter called `name`.
+    fn query(&self, name: &str) -> Option<String> {
+        let (_, query) = self.path.split_once('?')?;
+        query.split('&').find_map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let decode = |s: &str| roots::percent_decode(&s.replace('+', " ")).ok();
+            (decode(key)? == name).then(|| decode(value)).flatten()
+        })
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    ///
+    /// HTTP/1.1 defaults to keep-alive and HTTP/1.0 defaults to close, either can be
+    /// overridden by the `Connection` header.
+    fn keep_alive(&self) -> bool {
+        let options = || self.headers.get_list(CONNECTION_HEADER);
+        if options().any(|v| v.eq_ignore_ascii_case("close")) {
+            return false;
+        }
+        if options().any(|v| v.eq_ignore_ascii_case("keep-alive")) {
+            return true;
         }
+        self.http_version == "HTTP/1.1"
     }
 
     fn parse_up_to_header(header_string: &str) -> Result<Self> {
@@ -205,7 +496,7 @@ impl Request<'_> {
             let mut request_split = request_line.split_whitespace();
 
             if let Some(method_str) = request_split.next() {
-                method = HttpMethod::parse(&method_str)?;
+                method = HttpMethod::parse(method_str)?;
             } else {
                 return Err(anyhow!("Failed to get http method, no data found."));
             }
@@ -225,14 +516,19 @@ impl Request<'_> {
             return Err(anyhow!("Failed to parse request, no data found."));
         }
 
-        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut headers = Headers::new();
 
         for header in reader_lines {
-            if let Some((header_key, header_value)) = header.split_once(": ") {
-                headers.insert(header_key.into(), header_value.into());
+            if let Some((header_key, header_value)) = header.split_once(':') {
+                // No whitespace is allowed before the colon, a proxy in front of us
+                // might read `Transfer-Encoding :` differently than we would.
+                if !is_token(header_key) {
+                    return Err(anyhow!("Invalid header field name `{}`.", header_key));
+                }
+                headers.append(header_key.into(), header_value.trim().into());
             } else {
                 return Err(anyhow!(
-                    "Failed to parse header, values should be separated with `: `, got: {}.",
+                    "Failed to parse header, values should be separated with `:`, got: {}.",
                     header
                 ));
             }
@@ -244,287 +540,431 @@ impl Request<'_> {
             http_version,
             headers,
             body: None,
-            vars: None,
+            vars: HashMap::new(),
         })
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum HttpCode {
-    Ok,
-    NotFound,
-    InternalServerError,
-    BadRequest,
-    Created,
+/// What gets sent after the response headers.
+enum Body {
+    Bytes(Vec<u8>),
+    /// Copied from disk straight onto the connection as the response is written, so
+    /// the file never has to fit in memory.
+    File(File),
+    /// Generated while the response is written. The length isn't known up front, so
+    /// unless the handler sets `Content-Length` it goes out with chunked encoding.
+    Stream(Box<dyn Read + Send>),
 }
 
-impl HttpCode {
-    fn to_tcp_format(&self) -> &'static str {
+impl Body {
+    /// The length of the body, if it can be known before it's written.
+    fn len(&self) -> Result<Option<u64>> {
         match self {
-            HttpCode::Ok => "200 OK",
-            HttpCode::NotFound => "404 Not Found",
-            HttpCode::InternalServerError => "500 Internal Error",
-            HttpCode::BadRequest => "400 Bad Request",
-            HttpCode::Created => "201 Created",
+            Body::Bytes(bytes) => Ok(Some(bytes.len() as u64)),
+            Body::File(file) => Ok(Some(file.metadata()?.len())),
+            Body::Stream(_) => Ok(None),
+        }
+    }
+
+    /// Turns the body into a reader, for wrapping in another stream.
+    fn into_reader(self) -> Box<dyn Read + Send> {
+        match self {
+            Body::Bytes(bytes) => Box::new(std::io::Cursor::new(bytes)),
+            Body::File(file) => Box::new(file),
+            Body::Stream(reader) => reader,
         }
     }
 }
 
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::File(file) => f.debug_tuple("File").field(file).finish(),
+            Body::Stream(_) => f.write_str("Stream(..)"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Self {
+        Body::Bytes(bytes.into())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().into())
+    }
+}
+
 #[derive(Debug)]
 struct Response {
     pub http_code: HttpCode,
-    pub headers: HashMap<String, String>,
-    pub content: Option<Vec<u8>>,
+    pub headers: Headers,
+    pub content: Option<Body>,
 }
 
 impl Default for Response {
     fn default() -> Self {
         Self {
             http_code: HttpCode::Ok,
-            headers: HashMap::new(),
+            headers: Headers::new(),
             content: None,
         }
     }
 }
 
 impl Response {
-    fn write_to_stream(self, mut stream: &TcpStream) -> Result<usize> {
-        let mut stream_output = Vec::from([
-            IoSlice::new(b"HTTP/1.1 "),
-            IoSlice::new(self.http_code.to_tcp_format().as_bytes()),
-            IoSlice::new(b"\r\n"),
-        ]);
+    fn write_to_stream(
+        mut self,
+        connection: &mut Connection<impl Read + Write>,
+        keep_alive: bool,
+        http_version: &str,
+    ) -> Result<usize> {
+        let (stream_output, chunked) = self.serialize_head(
+            keep_alive,
+            http_version,
+            connection.requests_served,
+            &connection.limits,
+        )?;
+
+        // A partial write would leave the client waiting on the rest of the response,
+        // and any pipelined responses after it, so the whole thing has to go out.
+        let mut sent = stream_output.len();
+        let length = self.content_length();
+        let write_result = match self.content {
+            Some(body @ (Body::File(_) | Body::Stream(_))) => {
+                let mut reader = body.into_reader();
+                connection
+                    .write_all(&stream_output)
+                    .and_then(|()| match length {
+                        _ if chunked => connection.write_chunked_from(&mut reader),
+                        // A file can change size while it's being sent, but the client
+                        // is going by the length it was given.
+                        Some(length) => connection.copy_exactly(&mut reader, length),
+                        None => connection.copy_from(&mut reader),
+                    })
+                    .map(|copied| sent += copied as usize)
+            }
+            _ => connection.write_all(&stream_output),
+        };
+        match write_result {
+            Ok(()) => {
+                println!("Sent {sent} bytes back.");
+                Ok(sent)
+            }
+            Err(err) => Err(anyhow!("Could not write response to stream: {}", err)),
+        }
+    }
+
+    /// The length given in the `Content-Length` header, if there is one.
+    fn content_length(&self) -> Option<u64> {
+        self.headers.get(CONTENT_LENGTH_HEADER)?.parse().ok()
+    }
+
+    /// These never carry a body, so there's nothing to frame.
+    fn bodiless(&self) -> bool {
+        self.http_code.is_informational()
+            || matches!(self.http_code, HttpCode::NoContent | HttpCode::NotModified)
+    }
+
+    /// Whether the body can only be ended by closing the connection, which is the case
+    /// when its length isn't known and the client is too old for chunked encoding.
+    fn ends_with_close(&self, http_version: &str) -> bool {
+        !supports_chunked(http_version)
+            && !self.bodiless()
+            && !self.headers.contains_key(CONTENT_LENGTH_HEADER)
+            && matches!(self.content, Some(Body::Stream(_)))
+    }
+
+    /// Fills in the framing and connection headers, and returns the status line and
+    /// headers ready to send, followed by the body if it's already in memory. Also
+    /// returns whether the rest of the body needs to go out chunked.
+    fn serialize_head(
+        &mut self,
+        keep_alive: bool,
+        http_version: &str,
+        requests_served: usize,
+        limits: &Limits,
+    ) -> Result<(Vec<u8>, bool)> {
+        // The client can only find the end of the response without waiting for us to
+        // close the connection if it knows how long the body is, or gets it in chunks.
+        let mut chunked = false;
+        let keep_alive = keep_alive && !self.ends_with_close(http_version);
+        let bodiless = self.bodiless();
+        if bodiless {
+            self.content = None;
+        }
+        if !bodiless
+            && !self.headers.contains_key(CONTENT_LENGTH_HEADER)
+            && !self.headers.contains_key(TRANSFER_ENCODING_HEADER)
+        {
+            let length = match &self.content {
+                Some(content) => content.len()?,
+                None => Some(0),
+            };
+            match length {
+                Some(length) => {
+                    self.headers
+                        .insert(CONTENT_LENGTH_HEADER.into(), length.to_string());
+                }
+                None if supports_chunked(http_version) => {
+                    chunked = true;
+                    self.headers
+                        .insert(TRANSFER_ENCODING_HEADER.into(), "chunked".into());
+                }
+                // Closing the connection is all that's left to mark the end.
+                None => {}
+            }
+        }
+        // HTTP/1.0 clients don't know about chunks, which includes a HEAD response
+        // saying the body would be chunked.
+        if !supports_chunked(http_version) {
+            self.headers.remove(TRANSFER_ENCODING_HEADER);
+        }
+        if keep_alive {
+            self.headers
+                .insert(CONNECTION_HEADER.into(), "keep-alive".into());
+            self.headers.insert(
+                "Keep-Alive".into(),
+                format!(
+                    "timeout={}, max={}",
+                    limits.timeout.as_secs(),
+                    limits.max_requests - requests_served
+                ),
+            );
+        } else {
+            self.headers
+                .insert(CONNECTION_HEADER.into(), "close".into());
+        }
+
+        let mut stream_output = Vec::new();
+        stream_output.extend_from_slice(b"HTTP/1.1 ");
+        stream_output.extend_from_slice(self.http_code.to_tcp_format().as_bytes());
+        stream_output.extend_from_slice(b"\r\n");
 
         for (k, v) in self.headers.iter() {
-            stream_output.push(IoSlice::new(k.as_bytes()));
-            stream_output.push(IoSlice::new(b": "));
-            stream_output.push(IoSlice::new(v.as_bytes()));
-            stream_output.push(IoSlice::new(b"\r\n"));
+            stream_output.extend_from_slice(k.as_bytes());
+            stream_output.extend_from_slice(b": ");
+            stream_output.extend_from_slice(v.as_bytes());
+            stream_output.extend_from_slice(b"\r\n");
         }
 
-        stream_output.push(IoSlice::new(b"\r\n"));
+        stream_output.extend_from_slice(b"\r\n");
 
-        self.content
-            .as_ref()
-            .map(|s| stream_output.push(IoSlice::new(s)));
+        if let Some(Body::Bytes(content)) = &self.content {
+            stream_output.extend_from_slice(content);
+        }
 
-        let write_result = stream.write_vectored(&stream_output);
-        match write_result {
-            Ok(n) => {
-                println!("Sent {n} bytes back.");
-                Ok(n)
+        Ok((stream_output, chunked))
+    }
+}
+
+/// Opens a regular file for download, returning it along with its metadata.
+fn open_file(path: impl AsRef<std::path::Path>) -> Result<(File, std::fs::Metadata)> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(anyhow!("Not a regular file."));
+    }
+    Ok((file, metadata))
+}
+
+fn handle_connection(stream: Stream, server: &Server, config: &Cli, shutdown: &Shutdown) {
+    let limits = Limits::from(config);
+    // Doubles as the keep-alive idle timeout between requests.
+    if let Err(err) = stream.set_read_timeout(Some(limits.timeout)) {
+        eprintln!("Could not set up connection: {}", err);
+        return;
+    }
+    let mut connection = Connection::new(stream, limits);
+
+    loop {
+        let mut request = match connection.read_request() {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(err) => {
+                // There's no telling where the next request would start, so the
+                // connection can't be reused after this.
+                eprintln!("Failed to read request: {}", err);
+                if let Err(err) = err.into_response().write_to_stream(
+                    &mut connection,
+                    false,
+                    UNKNOWN_HTTP_VERSION,
+                ) {
+                    eprintln!("{}", err);
+                }
+                break;
             }
-            Err(err) => Err(anyhow!("Could not write response to stream: {}", err)),
+        };
+        connection.requests_served += 1;
+        let response = respond(server, &mut request, config);
+        // Checked after handling so a request that was in flight when the signal came
+        // still tells the client not to send another.
+        let keep_alive = request.keep_alive()
+            && connection.requests_served < connection.limits.max_requests
+            && !shutdown.is_draining()
+            && !response.ends_with_close(&request.http_version);
+
+        if let Err(err) =
+            response.write_to_stream(&mut connection, keep_alive, &request.http_version)
+        {
+            eprintln!("{}", err);
+            break;
         }
+
+        if !keep_alive {
+            break;
+        }
+    }
+}
+
+/// Runs a request through the server, turning a panicking handler into a 500.
+fn respond(server: &Server, request: &mut Request, config: &Cli) -> Response {
+    // A bug in one handler shouldn't cost the client its response.
+    let handled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        server.handle(request, config)
+    }));
+    let response = match handled {
+        Ok(response) => response,
+        Err(_) => HttpError::Internal(format!(
+            "Handler panicked on {} {}",
+            request.method.as_str(),
+            request.path
+        ))
+        .into_response(),
+    };
+    if request.method == HttpMethod::Head {
+        return strip_body(response);
     }
+    response
+}
+
+/// Drops the body from a response to a HEAD request, keeping the headers describing
+/// it so they match what a GET would have sent.
+/// Chunked encoding only came in with HTTP/1.1.
+fn supports_chunked(http_version: &str) -> bool {
+    http_version == "HTTP/1.1"
 }
 
-fn output_middleware(request: &Request, mut response: Response) -> Response {
-    if let Some(encoding) = request.headers.get("Accept-Encoding") {
-        if encoding.split(",").map(|v| v.trim()).any(|v| v == "gzip") {
-            // response.headers.insert("Content-Type".into(), "text/plain".into());
-            let mut compressed_content = GzEncoder::new(Vec::new(), Compression::default());
-            if let Some(content) = &response.content {
-                compressed_content.write_all(content).unwrap();
-                let c = compressed_content.finish().unwrap();
-                println!("Compressed len: {}", c.len().to_string());
-                response.headers.insert(CONTENT_LENGTH_HEADER.into(), c.len().to_string());
-                println!("Respons len: {:?}", c);
-                response.content = Some(c);
-                response.headers.insert(CONTENT_ENCODING_HEADER.into(), "gzip".into());
+fn strip_body(mut response: Response) -> Response {
+    if let Some(content) = response.content.take() {
+        if !response.headers.contains_key(CONTENT_LENGTH_HEADER) {
+            match content.len() {
+                Ok(Some(length)) => response
+                    .headers
+                    .insert(CONTENT_LENGTH_HEADER.into(), length.to_string()),
+                Ok(None) => response
+                    .headers
+                    .insert(TRANSFER_ENCODING_HEADER.into(), "chunked".into()),
+                // Leaving the length out is still a valid HEAD response.
+                Err(err) => eprintln!("Could not get body length: {}", err),
             }
         }
     }
     response
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
 
-fn handle_connection(stream: TcpStream, config: &Cli) {
-    let request = Request::from_stream(&stream).unwrap();
+    use super::*;
 
-    let mut response = Response {
-        http_code: HttpCode::Ok,
-        headers: HashMap::new(),
-        content: None,
-    };
+    /// A connection's stream that keeps whatever is written to it.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
 
-    match request.method {
-        HttpMethod::Get => {
-            match request.path.as_str() {
-                "/" => {}
-                path => {
-                    if path.starts_with("/echo") {
-                        let echo_word = path[1..].split_once('/').unwrap().1;
-
-                        response
-                            .headers
-                            .insert("Content-Type".into(), "text/plain".into());
-                        response
-                            .headers
-                            .insert("Content-Length".into(), format!("{}", echo_word.len()));
-
-                        response.content = Some(echo_word.as_bytes().into());
-                    } else if path.starts_with("/user-agent") {
-                        response
-                            .headers
-                            .insert("Content-Type".into(), "text/plain".into());
-                        response.headers.insert(
-                            "Content-Length".into(),
-                            format!("{}", request.headers.get("User-Agent").unwrap().len()),
-                        );
-
-                        response.content =
-                            Some(request.headers.get("User-Agent").unwrap().as_bytes().into());
-                    } else if path.starts_with("/files") {
-                        let path_split = path[1..].split_once('/');
-
-                        match path_split {
-                            Some((_, file_name)) => {
-                                match &config.directory {
-                                    Some(dir) => {
-                                        // Get file
-                                        match fs::read_to_string(format!(
-                                            "{}{}",
-                                            dir.display(),
-                                            file_name
-                                        )) {
-                                            Ok(data) => {
-                                                response.headers.insert(
-                                                    "Content-Type".into(),
-                                                    "application/octet-stream".into(),
-                                                );
-                                                response.headers.insert(
-                                                    "Content-Length".into(),
-                                                    format!("{}", data.len()),
-                                                );
-                                                response.content = Some(data.as_bytes().into())
-                                            }
-                                            Err(_) => response.http_code = HttpCode::NotFound,
-                                        };
-                                    }
-                                    None => {
-                                        eprintln!("CRITICAL: No files directory was set!");
-                                        response.http_code = HttpCode::InternalServerError;
-                                    }
-                                }
-                            }
-                            None => {
-                                response.http_code = HttpCode::Ok;
-                            }
-                        };
-                    } else {
-                        response.http_code = HttpCode::NotFound
-                    }
-                }
-            };
+    impl Read for Recorder {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
         }
-        HttpMethod::Post => {
-            if request.path.as_str().starts_with("/files") {
-                let path_split = request.path[1..].split_once("/");
-                if let Some(content_type) = request.headers.get(CONTENT_TYPE_HEADER) {
-                    if content_type != "application/octet-stream" {
-                        response.http_code = HttpCode::BadRequest;
-                        let response_msg = format!(
-                            "Unsupported content type `{}` expected `application/octet-stream`",
-                            content_type
-                        );
-                        response
-                            .headers
-                            .insert(CONTENT_LENGTH_HEADER.into(), response_msg.len().to_string());
-                        response.content = Some(response_msg.into());
-                    }
-                } else {
-                    response.http_code = HttpCode::BadRequest;
-                    let response_msg = "Expected content type header but got nothing.";
-                    response
-                        .headers
-                        .insert(CONTENT_LENGTH_HEADER.into(), response_msg.len().to_string());
-                    response.content = Some(response_msg.into());
-                }
+    }
 
-                if response.http_code != HttpCode::BadRequest {
-                    if let Some(content_length) = request.headers.get(CONTENT_LENGTH_HEADER) {
-                        debug_assert_eq!(
-                            content_length.parse::<usize>().unwrap(),
-                            request.body.to_owned().unwrap().len()
-                        );
-                    } else {
-                        response.http_code = HttpCode::BadRequest;
-                        let response_msg = format!("Missing {} header", CONTENT_LENGTH_HEADER);
-                        response
-                            .headers
-                            .insert(CONTENT_LENGTH_HEADER.into(), response_msg.len().to_string());
-                        response.content = Some(response_msg.into());
-                    }
-                }
+    impl Write for Recorder {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
 
-                if response.http_code != HttpCode::BadRequest {
-                    match path_split {
-                        Some((_, file_name)) => {
-                            match &config.directory {
-                                Some(dir) => {
-                                    // Get file
-                                    let mut filepath = dir.clone();
-                                    filepath.push(file_name);
-                                    println!("Not mutated {:?}", dir);
-                                    match File::create_new(filepath) {
-                                        Ok(mut file) => {
-                                            match file.write_all(
-                                                request
-                                                    .body
-                                                    .as_ref()
-                                                    .expect("No file data to upload.")
-                                                    .as_bytes(),
-                                            ) {
-                                                Ok(_) => {
-                                                    response.http_code = HttpCode::Created;
-                                                }
-                                                Err(err) => {
-                                                    eprintln!(
-                                                        "Failed to load file to {}, got error: {}",
-                                                        file_name,
-                                                        err
-                                                    );
-                                                    response.http_code =
-                                                        HttpCode::InternalServerError;
-                                                }
-                                            }
-                                        }
-                                        Err(err) => match err.kind() {
-                                            std::io::ErrorKind::AlreadyExists => {
-                                                response.http_code = HttpCode::BadRequest;
-                                                let response_msg =
-                                                    format!("File {} already exists.", file_name);
-                                                response.headers.insert(
-                                                    CONTENT_LENGTH_HEADER.into(),
-                                                    response_msg.len().to_string(),
-                                                );
-                                                response.content = Some(response_msg.into());
-                                            }
-                                            _ => {
-                                                eprintln!("CRITICAL: Could upload a user's file due to an internal server error: {}", err);
-                                                response.http_code = HttpCode::InternalServerError;
-                                            }
-                                        },
-                                    }
-                                }
-                                None => {
-                                    eprintln!("CRITICAL: No files directory was set!");
-                                    response.http_code = HttpCode::InternalServerError;
-                                }
-                            }
-                        }
-                        None => {
-                            response.http_code = HttpCode::BadRequest;
-                            response.content = Some("No file name sent in url, url should be formatted like /files/<file_name>".into());
-                        }
-                    }
-                }
-            } else {
-                response.http_code = HttpCode::BadRequest;
-            }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
-    response = output_middleware(&request, response);
-    response.write_to_stream(&stream).unwrap();
+
+    fn send(response: Response, http_version: &str) -> String {
+        let recorder = Recorder::default();
+        let limits = Limits::from(&Cli::parse_from(["test"]));
+        response
+            .write_to_stream(
+                &mut Connection::new(recorder.clone(), limits),
+                true,
+                http_version,
+            )
+            .unwrap();
+        let sent = recorder.0.lock().unwrap().clone();
+        String::from_utf8(sent).unwrap()
+    }
+
+    /// A body whose length isn't known until it's been read.
+    fn streamed() -> Response {
+        Response {
+            content: Some(Body::Stream(Box::new(Cursor::new(b"hello".to_vec())))),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chunks_bodies_of_unknown_length() {
+        assert!(!streamed().ends_with_close("HTTP/1.1"));
+        let sent = send(streamed(), "HTTP/1.1");
+        assert!(
+            sent.contains("\r\nTransfer-Encoding: chunked\r\n"),
+            "{}",
+            sent
+        );
+        assert!(sent.contains("\r\nConnection: keep-alive\r\n"), "{}", sent);
+        assert!(
+            sent.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"),
+            "{}",
+            sent
+        );
+    }
+
+    #[test]
+    fn closes_the_connection_instead_of_chunking_for_http_1_0() {
+        assert!(streamed().ends_with_close("HTTP/1.0"));
+        let sent = send(streamed(), "HTTP/1.0");
+        assert!(!sent.contains("Transfer-Encoding"), "{}", sent);
+        assert!(!sent.contains("Content-Length"), "{}", sent);
+        assert!(sent.contains("\r\nConnection: close\r\n"), "{}", sent);
+        assert!(sent.ends_with("\r\n\r\nhello"), "{}", sent);
+
+        // A HEAD response has no body to end, so there's no need to close.
+        let head = strip_body(streamed());
+        assert!(!head.ends_with_close("HTTP/1.0"));
+        let sent = send(head, "HTTP/1.0");
+        assert!(!sent.contains("Transfer-Encoding"), "{}", sent);
+        assert!(sent.ends_with("\r\n\r\n"), "{}", sent);
+
+        let mut known = streamed();
+        known
+            .headers
+            .insert(CONTENT_LENGTH_HEADER.into(), "5".into());
+        assert!(!known.ends_with_close("HTTP/1.0"));
+    }
 }
@@ -0,0 +1,136 @@
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::router::Router;
+use crate::validators;
+use crate::{
+    Body, Cli, HttpCode, Request, Response, CONTENT_ENCODING_HEADER, CONTENT_LENGTH_HEADER,
+};
+
+/// Wraps request handling, getting a look at the request before the handler runs and
+/// at the response after.
+///
+/// A middleware can change the request before passing it on with `next.run`, answer
+/// by itself without calling `next` at all, or change whatever response comes back.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next<'_>) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the pipeline after the current middleware, ending at the router.
+pub struct Next<'a> {
+    pub(crate) middleware: &'a [Box<dyn Middleware>],
+    pub(crate) router: &'a Router,
+    pub(crate) config: &'a Cli,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((current, rest)) => current.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    ..self
+                },
+            ),
+            None => self.router.handle(request, self.config),
+        }
+    }
+}
+
+/// Gzips response bodies for clients that accept it.
+pub struct Gzip;
+
+impl Middleware for Gzip {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let mut response = next.run(request);
+
+        let accepts_gzip = request
+            .headers
+            .get_list("Accept-Encoding")
+            .any(|v| v.eq_ignore_ascii_case("gzip"));
+        let compressible = (response.content.is_some()
+            || response.http_code == HttpCode::NotModified)
+            && response.http_code != HttpCode::PartialContent;
+        // Whether the body gets compressed is down to `Accept-Encoding`, so a cache
+        // mustn't hand this response to a client that sent a different one.
+        if compressible {
+            response.headers.add_to_list("Vary", "Accept-Encoding");
+        }
+        // The compressed bytes aren't the file's, so its ETag can only say they're
+        // equivalent. A 304 has to give back the same ETag the full response would have.
+        if accepts_gzip && compressible {
+            if let Some(etag) = response.headers.get("ETag") {
+                let etag = validators::weaken(etag);
+                response.headers.insert("ETag".into(), etag);
+            }
+        }
+
+        // A range is of the uncompressed file, compressing it would make the offsets wrong.
+        if accepts_gzip && response.http_code != HttpCode::PartialContent {
+            let mut compressed_content = GzEncoder::new(Vec::new(), Compression::default());
+            if let Some(Body::File(_) | Body::Stream(_)) = &response.content {
+                // Compress on the fly rather than reading it all in, the compressed
+                // length isn't known until the end so it has to go out chunked.
+                let reader = response.content.take().unwrap().into_reader();
+                response.content = Some(Body::Stream(Box::new(flate2::read::GzEncoder::new(
+                    reader,
+                    Compression::default(),
+                ))));
+                response.headers.remove(CONTENT_LENGTH_HEADER);
+                response
+                    .headers
+                    .insert(CONTENT_ENCODING_HEADER.into(), "gzip".into());
+            } else if let Some(Body::Bytes(content)) = &response.content {
+                compressed_content.write_all(content).unwrap();
+                let c = compressed_content.finish().unwrap();
+                println!("Compressed len: {}", c.len());
+                response
+                    .headers
+                    .insert(CONTENT_LENGTH_HEADER.into(), c.len().to_string());
+                response.content = Some(c.into());
+                response
+                    .headers
+                    .insert(CONTENT_ENCODING_HEADER.into(), "gzip".into());
+            }
+        }
+        response
+    }
+}
+
+/// Prints a line for every request with the status it got.
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let started = std::time::Instant::now();
+        let method = request.method.clone();
+        let path = request.path.clone();
+
+        let response = next.run(request);
+        let line = format!(
+            "{} {} -> {} ({:?})",
+            method.as_str(),
+            path,
+            response.http_code.to_tcp_format(),
+            started.elapsed()
+        );
+        if response.http_code.is_server_error() {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+        response
+    }
+}
@@ -0,0 +1,64 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::Scope;
+
+/// A fixed number of worker threads taking jobs off a bounded queue.
+///
+/// Workers live in the given thread scope, so jobs can borrow from outside it. Dropping
+/// the pool closes the queue, and the workers exit once it's been drained.
+pub struct WorkerPool<T> {
+    sender: SyncSender<T>,
+}
+
+impl<T: Send> WorkerPool<T> {
+    pub fn new<'scope>(
+        scope: &'scope Scope<'scope, '_>,
+        workers: usize,
+        queue_size: usize,
+        handler: impl Fn(T) + Send + Sync + 'scope,
+    ) -> Self
+    where
+        T: 'scope,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        for id in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            let handler = Arc::clone(&handler);
+            scope.spawn(move || work(id, &receiver, &*handler));
+        }
+
+        Self { sender }
+    }
+
+    /// Queues `job` for the next free worker, handing it back if the queue is full.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job) | TrySendError::Disconnected(job)) => Err(job),
+        }
+    }
+}
+
+fn work<T>(id: usize, receiver: &Mutex<Receiver<T>>, handler: &impl Fn(T)) {
+    loop {
+        // The lock is only held while waiting, never while handling the job.
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => {
+                // A panicking job would otherwise take its worker down with it, leaving
+                // the pool a worker short for good.
+                if panic::catch_unwind(AssertUnwindSafe(|| handler(job))).is_err() {
+                    eprintln!("Worker {id} panicked handling a job, carrying on.");
+                }
+            }
+            Err(_) => {
+                println!("Worker {id} shutting down.");
+                return;
+            }
+        }
+    }
+}
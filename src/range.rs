@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+/// More ranges than this in one request are more likely an attempt to make us do a lot
+/// of small reads than a real client, so the whole file is sent instead.
+const MAX_RANGES: usize = 32;
+
+/// A range of bytes from a `Range: bytes=` header, inclusive at both ends and within
+/// the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The value of a `Content-Range` header for this range.
+    pub fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, length)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// Not a range we understand, the whole file is sent as if there was no header.
+    Ignored,
+    /// The ranges to send, sorted with any overlapping ones merged together.
+    Satisfiable(Vec<ByteRange>),
+    /// None of the ranges overlap the file.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a file `length` bytes long.
+///
+/// Accepts `start-end`, `start-` and suffix `-length` ranges, any number of them
+/// separated by commas.
+pub fn parse(header: &str, length: u64) -> Ranges {
+    let specs = match header.split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Ignored,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        count += 1;
+        if count > MAX_RANGES {
+            return Ranges::Ignored;
+        }
+        let Some((start, end)) = spec.split_once('-') else {
+            return Ranges::Ignored;
+        };
+        let number = |n: &str| {
+            n.parse::<u64>()
+                .ok()
+                .filter(|_| n.bytes().all(|b| b.is_ascii_digit()))
+        };
+
+        let range = match (start, end) {
+            ("", suffix) => match number(suffix) {
+                Some(0) => None,
+                Some(suffix) if length > 0 => Some(ByteRange {
+                    start: length.saturating_sub(suffix),
+                    end: length - 1,
+                }),
+                Some(_) => None,
+                None => return Ranges::Ignored,
+            },
+            (start, "") => match number(start) {
+                Some(start) if start < length => Some(ByteRange {
+                    start,
+                    end: length - 1,
+                }),
+                Some(_) => None,
+                None => return Ranges::Ignored,
+            },
+            (start, end) => match (number(start), number(end)) {
+                (Some(start), Some(end)) if start > end => return Ranges::Ignored,
+                (Some(start), Some(end)) if start < length => Some(ByteRange {
+                    start,
+                    end: end.min(length - 1),
+                }),
+                (Some(_), Some(_)) => None,
+                _ => return Ranges::Ignored,
+            },
+        };
+        ranges.extend(range);
+    }
+
+    if count == 0 {
+        return Ranges::Ignored;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end + 1 => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ranges::Satisfiable(merged)
+}
+
+/// A `multipart/byteranges` body, reading each range out of the file as it goes
+/// rather than all at once.
+pub struct Multipart {
+    file: File,
+    parts: VecDeque<Part>,
+}
+
+enum Part {
+    Text(Cursor<Vec<u8>>),
+    Section { start: u64, remaining: u64 },
+}
+
+impl Multipart {
+    /// Returns the body along with its length.
+    pub fn new(
+        file: File,
+        ranges: &[ByteRange],
+        length: u64,
+        content_type: &str,
+        boundary: &str,
+    ) -> (Self, u64) {
+        let mut parts = VecDeque::new();
+        let mut total = 0;
+        for range in ranges {
+            let head = format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                range.content_range(length)
+            );
+            total += head.len() as u64 + range.len();
+            parts.push_back(Part::Text(Cursor::new(head.into_bytes())));
+            parts.push_back(Part::Section {
+                start: range.start,
+                remaining: range.len(),
+            });
+        }
+        let end = format!("\r\n--{}--\r\n", boundary);
+        total += end.len() as u64;
+        parts.push_back(Part::Text(Cursor::new(end.into_bytes())));
+
+        (Self { file, parts }, total)
+    }
+}
+
+impl Read for Multipart {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Text(text) => text.read(buf)?,
+                Part::Section { start, remaining } => {
+                    if *remaining == 0 {
+                        0
+                    } else {
+                        self.file.seek(SeekFrom::Start(*start))?;
+                        let wanted = buf.len().min(*remaining as usize);
+                        let read = self.file.read(&mut buf[..wanted])?;
+                        if read == 0 {
+                            return Err(std::io::ErrorKind::UnexpectedEof.into());
+                        }
+                        *start += read as u64;
+                        *remaining -= read as u64;
+                        read
+                    }
+                }
+            };
+            if read > 0 {
+                return Ok(read);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn satisfiable(header: &str, length: u64) -> Vec<(u64, u64)> {
+        match parse(header, length) {
+            Ranges::Satisfiable(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            other => panic!("{} gave {:?}", header, other),
+        }
+    }
+
+    #[test]
+    fn parses_each_kind_of_range() {
+        assert_eq!(satisfiable("bytes=0-4", 10), [(0, 4)]);
+        assert_eq!(satisfiable("bytes=5-", 10), [(5, 9)]);
+        assert_eq!(satisfiable("bytes=-3", 10), [(7, 9)]);
+        assert_eq!(satisfiable("Bytes = 2-2", 10), [(2, 2)]);
+        // Ranges running off the end are cut short, suffixes longer than the file
+        // are the whole file.
+        assert_eq!(satisfiable("bytes=8-100", 10), [(8, 9)]);
+        assert_eq!(satisfiable("bytes=-100", 10), [(0, 9)]);
+    }
+
+    #[test]
+    fn sorts_and_merges_ranges() {
+        assert_eq!(satisfiable("bytes=6-7, 0-1", 10), [(0, 1), (6, 7)]);
+        assert_eq!(satisfiable("bytes=0-4,2-6", 10), [(0, 6)]);
+        // Adjacent ranges are as good as overlapping.
+        assert_eq!(satisfiable("bytes=0-1,2-3", 10), [(0, 3)]);
+        assert_eq!(satisfiable("bytes=0-9,3-4,-2", 10), [(0, 9)]);
+        // Unsatisfiable ranges are dropped as long as one of them is satisfiable.
+        assert_eq!(satisfiable("bytes=0-1,50-60", 10), [(0, 1)]);
+    }
+
+    #[test]
+    fn ignores_what_it_does_not_understand() {
+        for header in [
+            "bytes=4-2",
+            "bytes=a-b",
+            "bytes=-",
+            "bytes=1",
+            "bytes=+1-2",
+            "bytes=",
+            "lines=0-1",
+            "0-1",
+        ] {
+            assert_eq!(parse(header, 10), Ranges::Ignored, "{}", header);
+        }
+
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(parse(&format!("bytes={}", many), 1000), Ranges::Ignored);
+        let just_enough = (0..MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(
+            satisfiable(&format!("bytes={}", just_enough), 1000).len(),
+            MAX_RANGES
+        );
+    }
+
+    #[test]
+    fn refuses_ranges_outside_the_file() {
+        assert_eq!(parse("bytes=10-20", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=10-", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn multipart_length_matches_what_is_sent() {
+        let path = std::env::temp_dir().join(format!("http-server-range-{}", std::process::id()));
+        std::fs::write(&path, "0123456789").unwrap();
+
+        let ranges = satisfiable("bytes=0-1,5-,-1", 10)
+            .into_iter()
+            .map(|(start, end)| ByteRange { start, end })
+            .collect::<Vec<_>>();
+        let file = File::open(&path).unwrap();
+        let (mut body, length) = Multipart::new(file, &ranges, 10, "text/plain", "BOUNDARY");
+        let mut sent = String::new();
+        body.read_to_string(&mut sent).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(sent.len() as u64, length);
+        assert_eq!(
+            sent,
+            "\r\n--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 5-9/10\r\n\r\n56789\
+             \r\n--BOUNDARY--\r\n"
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::error::HttpError;
+use crate::{Cli, DEFAULT_FILES_DIR};
+
+/// A directory that `/files` serves from, and takes uploads into unless it's read-only.
+///
+/// The default root, from `--directory`, has an empty name and answers at `/files/`.
+/// Named roots answer at `/files/<name>/`, so `--root docs=/srv/docs` serves
+/// `/srv/docs/guide.html` as `/files/docs/guide.html`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Root {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Parses a `--root` argument, `NAME=PATH`.
+pub fn parse_root(root: &str) -> Result<Root, String> {
+    match root.split_once('=') {
+        Some((name, path)) if !path.is_empty() => Ok(Root {
+            name: name.into(),
+            path: path.into(),
+            read_only: false,
+        }),
+        _ => Err(format!("`{}` should look like NAME=PATH", root)),
+    }
+}
+
+/// Every root the settings ask for, with their paths canonicalized so nothing about
+/// them changes under us, e.g. through a symlink being swapped out.
+///
+/// The default root is only left out when there are named roots and no
+/// `--directory`, and is created if it's the built in one and doesn't exist yet,
+/// unless this is only `--check-config`.
+pub fn build(config: &Cli) -> Result<Vec<Root>> {
+    let mut roots = Vec::new();
+    match &config.directory {
+        Some(directory) => roots.push(Root {
+            name: String::new(),
+            path: directory.clone(),
+            read_only: false,
+        }),
+        // Only checking the settings shouldn't leave anything behind, and there's
+        // nothing to check about a directory that would be created on startup.
+        None if config.root.is_empty()
+            && config.check_config
+            && !Path::new(DEFAULT_FILES_DIR).exists() => {}
+        None if config.root.is_empty() => {
+            std::fs::create_dir_all(DEFAULT_FILES_DIR)
+                .map_err(|err| anyhow!("Could not create {}: {}", DEFAULT_FILES_DIR, err))?;
+            roots.push(Root {
+                name: String::new(),
+                path: DEFAULT_FILES_DIR.into(),
+                read_only: false,
+            });
+        }
+        None => {}
+    }
+
+    for root in &config.root {
+        if root.name.is_empty() || root.name.contains('/') {
+            return Err(anyhow!(
+                "Root name `{}` should be a single, non-empty path segment",
+                root.name
+            ));
+        }
+        if roots.iter().any(|other: &Root| other.name == root.name) {
+            return Err(anyhow!("There's more than one root called `{}`", root.name));
+        }
+        roots.push(root.clone());
+    }
+
+    for root in &mut roots {
+        root.path = root
+            .path
+            .canonicalize()
+            .map_err(|err| anyhow!("Could not find root {}: {}", root.path.display(), err))?;
+        if !root.path.is_dir() {
+            return Err(anyhow!("Root {} isn't a directory", root.path.display()));
+        }
+        root.read_only |= config.read_only;
+    }
+    Ok(roots)
+}
+
+/// The root a path under `/files/` is in, along with the rest of the path within it.
+fn resolve<'a, 'p>(config: &'a Cli, path: &'p str) -> Option<(&'a Root, &'p str)> {
+    let (first, rest) = path.split_once('/').unwrap_or((path, ""));
+    config
+        .roots
+        .iter()
+        .find(|root| !root.name.is_empty() && root.name == first)
+        .map(|root| (root, rest))
+        .or_else(|| {
+            let default = config.roots.iter().find(|root| root.name.is_empty())?;
+            Some((default, path))
+        })
+}
+
+/// A file asked for under `/files`, somewhere inside one of the roots.
+#[derive(Debug)]
+pub struct Located<'a> {
+    pub root: &'a Root,
+    /// Canonicalized as far as the file, or its nearest ancestor, exists.
+    pub path: PathBuf,
+    /// The path within the root, as the client asked for it once decoded.
+    pub name: String,
+}
+
+/// Works out which file a `/files` URL path points to, making sure it's inside a root.
+///
+/// The path is percent-decoded and `.` and `..` segments resolved before picking the
+/// root, so an encoded `..%2F` can't be used to climb out. Anything that would still
+/// end up outside the root, including through a symlink inside it, is refused with a
+/// 403.
+pub fn locate<'a>(config: &'a Cli, raw_path: &str) -> Result<Located<'a>, HttpError> {
+    let segments = normalize(raw_path)?;
+    let path = segments.join("/");
+    let (root, name) = resolve(config, &path)
+        .ok_or_else(|| HttpError::NotFound(format!("No files are served at /files/{}", path)))?;
+    let name = name.to_string();
+
+    let requested = root.path.join(&name);
+    let path = confine(&root.path, &requested)?;
+    Ok(Located { root, path, name })
+}
+
+/// Splits a raw URL path into decoded segments, resolving `.` and `..`.
+fn normalize(raw_path: &str) -> Result<Vec<String>, HttpError> {
+    let decoded = percent_decode(raw_path)?;
+    if decoded.contains('\0') {
+        return Err(HttpError::BadRequest("Path contains a NUL byte.".into()));
+    }
+
+    let mut segments: Vec<String> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(HttpError::Forbidden(format!(
+                        "{} is outside of the served files.",
+                        raw_path
+                    )));
+                }
+            }
+            segment => segments.push(segment.into()),
+        }
+    }
+    Ok(segments)
+}
+
+/// Decodes `%XX` escapes, which have to make valid UTF-8 between them.
+pub fn percent_decode(raw: &str) -> Result<String, HttpError> {
+    let malformed = || HttpError::BadRequest(format!("Malformed percent-encoding in {}.", raw));
+    let mut decoded = Vec::with_capacity(raw.len());
+    let mut bytes = raw.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let (Some(high), Some(low)) = (bytes.next(), bytes.next()) else {
+            return Err(malformed());
+        };
+        // Not `from_str_radix`, which would take a sign as well.
+        let digit = |b: u8| (b as char).to_digit(16).ok_or_else(malformed);
+        decoded.push((digit(high)? * 16 + digit(low)?) as u8);
+    }
+    String::from_utf8(decoded)
+        .map_err(|_| HttpError::BadRequest(format!("{} isn't valid UTF-8 once decoded.", raw)))
+}
+
+/// Canonicalizes `path`, or if it doesn't exist yet its nearest ancestor that does,
+/// and checks the result is still inside `root`.
+///
+/// Symlinks are fine as long as they point somewhere else in the root.
+pub fn confine(root: &Path, path: &Path) -> Result<PathBuf, HttpError> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    let canonical = loop {
+        match existing.canonicalize() {
+            Ok(canonical) => break canonical,
+            // A path that runs through a file fails with something other than
+            // NotFound, but doesn't exist all the same.
+            Err(err)
+                if err.kind() == ErrorKind::NotFound || existing.symlink_metadata().is_err() =>
+            {
+                missing.push(existing.file_name().unwrap_or_default());
+                existing = existing
+                    .parent()
+                    .ok_or_else(|| HttpError::NotFound("Root no longer exists.".into()))?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    if !canonical.starts_with(root) {
+        return Err(HttpError::Forbidden(format!(
+            "{} is outside of the served files.",
+            path.strip_prefix(root).unwrap_or(path).display()
+        )));
+    }
+    Ok(missing
+        .iter()
+        .rev()
+        .fold(canonical, |path, part| path.join(part)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use clap::Parser;
+
+    use super::*;
+
+    /// A fresh root with a file, a subdirectory, and symlinks both inside and out.
+    fn setup(test: &str) -> (PathBuf, Cli) {
+        let base =
+            std::env::temp_dir().join(format!("http-server-roots-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(root.join("file.txt"), "inside").unwrap();
+        std::fs::write(base.join("outside/secret.txt"), "outside").unwrap();
+        symlink(base.join("outside"), root.join("escape")).unwrap();
+        symlink(root.join("sub"), root.join("shortcut")).unwrap();
+
+        let mut config = Cli::parse_from(["test", "--directory", root.to_str().unwrap()]);
+        config.roots = build(&config).unwrap();
+        (base, config)
+    }
+
+    fn located(config: &Cli, raw_path: &str) -> Result<PathBuf, HttpError> {
+        locate(config, raw_path).map(|located| located.path)
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc").unwrap(), "a b/c");
+        assert_eq!(percent_decode("%e2%9c%93").unwrap(), "✓");
+        assert!(matches!(
+            percent_decode("%2"),
+            Err(HttpError::BadRequest(_))
+        ));
+        assert!(matches!(
+            percent_decode("%zz"),
+            Err(HttpError::BadRequest(_))
+        ));
+        assert!(matches!(
+            percent_decode("%+1"),
+            Err(HttpError::BadRequest(_))
+        ));
+        assert!(matches!(
+            percent_decode("%ff"),
+            Err(HttpError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn normalizes_dot_segments() {
+        assert_eq!(normalize("a/./b//c/").unwrap(), ["a", "b", "c"]);
+        assert_eq!(normalize("a/../b").unwrap(), ["b"]);
+        assert_eq!(normalize("/etc/passwd").unwrap(), ["etc", "passwd"]);
+        assert!(normalize("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_climbing_out_of_the_root() {
+        let (base, config) = setup("climb");
+        for raw_path in [
+            "../outside/secret.txt",
+            "../../etc/passwd",
+            "sub/../../outside/secret.txt",
+            "%2e%2e/outside/secret.txt",
+            "%2E%2E%2Foutside%2Fsecret.txt",
+            "..%2f..%2fetc%2fpasswd",
+            ".%2e/outside/secret.txt",
+        ] {
+            assert!(
+                matches!(located(&config, raw_path), Err(HttpError::Forbidden(_))),
+                "{} wasn't refused",
+                raw_path
+            );
+        }
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn keeps_odd_but_harmless_paths_inside() {
+        let (base, config) = setup("harmless");
+        let root = &config.roots[0].path;
+        assert_eq!(located(&config, "file.txt").unwrap(), root.join("file.txt"));
+        assert_eq!(
+            located(&config, "/file.txt").unwrap(),
+            root.join("file.txt")
+        );
+        assert_eq!(
+            located(&config, "sub/../file.txt").unwrap(),
+            root.join("file.txt")
+        );
+        assert_eq!(
+            located(&config, "%66ile.txt").unwrap(),
+            root.join("file.txt")
+        );
+        // Double encoding only decodes once, leaving a file literally called `%2e%2e`.
+        assert_eq!(located(&config, "%252e%252e").unwrap(), root.join("%2e%2e"));
+        // `\` is just another character in a file name here.
+        assert_eq!(
+            located(&config, "..%5cfile").unwrap(),
+            root.join("..\\file")
+        );
+        assert!(matches!(
+            located(&config, "file%00.txt"),
+            Err(HttpError::BadRequest(_))
+        ));
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn follows_symlinks_only_within_the_root() {
+        let (base, config) = setup("symlinks");
+        let root = &config.roots[0].path;
+        assert_eq!(
+            located(&config, "shortcut/new.txt").unwrap(),
+            root.join("sub/new.txt")
+        );
+        assert!(matches!(
+            located(&config, "escape/secret.txt"),
+            Err(HttpError::Forbidden(_))
+        ));
+        assert!(matches!(
+            located(&config, "escape"),
+            Err(HttpError::Forbidden(_))
+        ));
+        // Even for files that don't exist yet, like uploads.
+        assert!(matches!(
+            located(&config, "escape/new/file.txt"),
+            Err(HttpError::Forbidden(_))
+        ));
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn picks_named_roots_after_decoding() {
+        let (base, mut config) = setup("named");
+        config.root =
+            vec![parse_root(&format!("docs={}", base.join("outside").display())).unwrap()];
+        config.roots = build(&config).unwrap();
+        let docs = base.join("outside").canonicalize().unwrap();
+        assert_eq!(
+            located(&config, "docs/secret.txt").unwrap(),
+            docs.join("secret.txt")
+        );
+        assert_eq!(
+            located(&config, "%64ocs/secret.txt").unwrap(),
+            docs.join("secret.txt")
+        );
+        // Climbing out of a named root lands back in the default one.
+        assert_eq!(
+            located(&config, "docs/../file.txt").unwrap(),
+            config.roots[0].path.join("file.txt")
+        );
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
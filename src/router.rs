@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+
+use crate::error::HttpError;
+use crate::{Cli, HttpCode, HttpMethod, Request, Response};
+
+pub type Handler = Box<dyn Fn(&Request, &Cli) -> Result<Response, HttpError> + Send + Sync>;
+
+/// One piece of a route's path, between slashes.
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    /// `{name}`, matches exactly one non-empty segment.
+    Var(String),
+    /// `{*name}`, matches everything that's left, including nothing at all.
+    Rest(String),
+}
+
+struct Route {
+    method: HttpMethod,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    /// The path variables, if `path` matches this route.
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut vars = HashMap::new();
+        let mut parts = path.trim_start_matches('/').split('/');
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Var(name) => match parts.next()? {
+                    "" => return None,
+                    part => {
+                        vars.insert(name.clone(), part.into());
+                    }
+                },
+                Segment::Rest(name) => {
+                    vars.insert(name.clone(), parts.by_ref().collect::<Vec<_>>().join("/"));
+                }
+            }
+        }
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(vars),
+        }
+    }
+}
+
+/// Picks the handler for a request from its method and path.
+///
+/// Routes are written like `/echo/{word}` or `/files/{*path}`, and whatever the
+/// variables match ends up in `Request::vars`. HEAD is answered by the GET handler
+/// and OPTIONS by the router itself, and a path that exists but not for the requested
+/// method gets a 405 listing the methods that would have worked.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(
+        &mut self,
+        method: HttpMethod,
+        pattern: &str,
+        handler: impl Fn(&Request, &Cli) -> Result<Response, HttpError> + Send + Sync + 'static,
+    ) {
+        let mut segments = Vec::new();
+        let mut parts = pattern.trim_start_matches('/').split('/').peekable();
+
+        while let Some(part) = parts.next() {
+            let segment = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) => match name.strip_prefix('*') {
+                    Some(name) => {
+                        assert!(
+                            parts.peek().is_none(),
+                            "`{{*{}}}` has to be the last segment of route `{}`",
+                            name,
+                            pattern
+                        );
+                        Segment::Rest(name.into())
+                    }
+                    None => Segment::Var(name.into()),
+                },
+                None => Segment::Literal(part.into()),
+            };
+            segments.push(segment);
+        }
+
+        self.routes.push(Route {
+            method,
+            pattern: pattern.into(),
+            segments,
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Removes every route with `pattern`, whatever its method, returning whether there
+    /// were any.
+    pub fn remove(&mut self, pattern: &str) -> bool {
+        let before = self.routes.len();
+        self.routes.retain(|route| route.pattern != pattern);
+        self.routes.len() != before
+    }
+
+    pub fn handle(&self, request: &mut Request, config: &Cli) -> Response {
+        let mut response = Response::default();
+
+        if let HttpMethod::Extension(method) = &request.method {
+            response.http_code = HttpCode::NotImplemented;
+            response.content = Some(format!("Method {} is not supported.", method).into());
+            return response;
+        }
+
+        let path = request
+            .path
+            .split_once('?')
+            .map_or(request.path.as_str(), |(path, _query)| path);
+
+        // `OPTIONS *` asks about the server as a whole rather than any one path.
+        if request.method == HttpMethod::Options && path == "*" {
+            let methods = self.routes.iter().map(|route| &route.method);
+            response.headers.insert("Allow".into(), allow(methods));
+            return response;
+        }
+
+        let matching = self
+            .routes
+            .iter()
+            .filter_map(|route| Some((route, route.matches(path)?)))
+            .collect::<Vec<_>>();
+
+        if matching.is_empty() {
+            response.http_code = HttpCode::NotFound;
+            return response;
+        }
+
+        let found = matching
+            .iter()
+            .find(|(route, _)| route.method == request.method)
+            .or_else(|| {
+                matching.iter().find(|(route, _)| {
+                    request.method == HttpMethod::Head && route.method == HttpMethod::Get
+                })
+            });
+
+        match found {
+            Some((route, vars)) => {
+                request.vars = vars.clone();
+                (route.handler)(request, config).unwrap_or_else(HttpError::into_response)
+            }
+            None => {
+                if request.method != HttpMethod::Options {
+                    response.http_code = HttpCode::MethodNotAllowed;
+                }
+                let methods = matching.iter().map(|(route, _)| &route.method);
+                response.headers.insert("Allow".into(), allow(methods));
+                response
+            }
+        }
+    }
+}
+
+/// The value for an `Allow` header, including the methods the router answers itself.
+fn allow<'a>(methods: impl Iterator<Item = &'a HttpMethod>) -> String {
+    let mut allowed: Vec<&HttpMethod> = Vec::new();
+    for method in methods {
+        if !allowed.contains(&method) {
+            allowed.push(method);
+        }
+        if *method == HttpMethod::Get && !allowed.contains(&&HttpMethod::Head) {
+            allowed.push(&HttpMethod::Head);
+        }
+    }
+    if !allowed.contains(&&HttpMethod::Options) {
+        allowed.push(&HttpMethod::Options);
+    }
+
+    allowed
+        .iter()
+        .map(|method| method.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Body;
+
+    fn request(method: &str, path: &str) -> Request {
+        let head = format!("{} {} HTTP/1.1", method, path);
+        Request::parse_head(head.as_bytes()).unwrap().0
+    }
+
+    /// A router whose handlers answer with the route they were registered for and
+    /// the variables they got.
+    fn router() -> Router {
+        let mut router = Router::new();
+        for (method, pattern) in [
+            (HttpMethod::Get, "/"),
+            (HttpMethod::Get, "/echo/{word}"),
+            (HttpMethod::Get, "/files/{*path}"),
+            (HttpMethod::Post, "/files/{*path}"),
+            (HttpMethod::Delete, "/files/{*path}"),
+        ] {
+            let name = format!("{} {}", method.as_str(), pattern);
+            router.add(method, pattern, move |request, _config| {
+                let mut vars = request.vars.iter().collect::<Vec<_>>();
+                vars.sort();
+                Ok(Response {
+                    content: Some(format!("{} {:?}", name, vars).into()),
+                    ..Response::default()
+                })
+            });
+        }
+        router
+    }
+
+    fn handle(router: &Router, method: &str, path: &str) -> Response {
+        router.handle(&mut request(method, path), &Cli::parse_from(["test"]))
+    }
+
+    fn content(response: &Response) -> String {
+        match &response.content {
+            Some(Body::Bytes(bytes)) => String::from_utf8(bytes.clone()).unwrap(),
+            other => panic!("Unexpected body {:?}", other),
+        }
+    }
+
+    fn vars(router: &Router, pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        let route = router.routes.iter().find(|r| r.pattern == pattern).unwrap();
+        let mut vars = route.matches(path)?.into_iter().collect::<Vec<_>>();
+        vars.sort();
+        Some(vars)
+    }
+
+    fn var(name: &str, value: &str) -> (String, String) {
+        (name.into(), value.into())
+    }
+
+    #[test]
+    fn matches_variables() {
+        let router = router();
+        assert_eq!(
+            vars(&router, "/echo/{word}", "/echo/hello"),
+            Some(vec![var("word", "hello")])
+        );
+        assert_eq!(vars(&router, "/echo/{word}", "/echo/"), None);
+        assert_eq!(vars(&router, "/echo/{word}", "/echo"), None);
+        assert_eq!(vars(&router, "/echo/{word}", "/echo/a/b"), None);
+        assert_eq!(vars(&router, "/echo/{word}", "/other/a"), None);
+    }
+
+    #[test]
+    fn matches_the_rest_of_the_path() {
+        let router = router();
+        assert_eq!(
+            vars(&router, "/files/{*path}", "/files/a/b/c.txt"),
+            Some(vec![var("path", "a/b/c.txt")])
+        );
+        assert_eq!(
+            vars(&router, "/files/{*path}", "/files/"),
+            Some(vec![var("path", "")])
+        );
+        assert_eq!(
+            vars(&router, "/files/{*path}", "/files/a//b/"),
+            Some(vec![var("path", "a//b/")])
+        );
+        assert_eq!(
+            vars(&router, "/files/{*path}", "/files"),
+            Some(vec![var("path", "")])
+        );
+        assert_eq!(vars(&router, "/files/{*path}", "/other/a"), None);
+    }
+
+    #[test]
+    fn matches_the_root_only_at_the_root() {
+        let router = router();
+        assert_eq!(vars(&router, "/", "/"), Some(vec![]));
+        assert_eq!(vars(&router, "/", "/echo"), None);
+    }
+
+    #[test]
+    fn ignores_the_query() {
+        let router = router();
+        let response = handle(&router, "GET", "/echo/hi?word=no");
+        assert_eq!(content(&response), r#"GET /echo/{word} [("word", "hi")]"#);
+        assert_eq!(handle(&router, "GET", "/?x=1").http_code, HttpCode::Ok);
+    }
+
+    #[test]
+    fn answers_head_with_the_get_handler() {
+        let router = router();
+        let response = handle(&router, "HEAD", "/echo/hi");
+        assert_eq!(response.http_code, HttpCode::Ok);
+        assert_eq!(content(&response), r#"GET /echo/{word} [("word", "hi")]"#);
+    }
+
+    #[test]
+    fn lists_the_allowed_methods_on_405() {
+        let router = router();
+        let response = handle(&router, "PUT", "/files/a");
+        assert_eq!(response.http_code, HttpCode::MethodNotAllowed);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, POST, DELETE, OPTIONS")
+        );
+
+        let response = handle(&router, "POST", "/echo/hi");
+        assert_eq!(response.http_code, HttpCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        assert_eq!(
+            handle(&router, "GET", "/nowhere").http_code,
+            HttpCode::NotFound
+        );
+    }
+
+    #[test]
+    fn answers_options() {
+        let router = router();
+        let response = handle(&router, "OPTIONS", "/echo/hi");
+        assert_eq!(response.http_code, HttpCode::Ok);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let response = handle(&router, "OPTIONS", "*");
+        assert_eq!(response.http_code, HttpCode::Ok);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, POST, DELETE, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_methods() {
+        let router = router();
+        let response = handle(&router, "BREW", "/");
+        assert_eq!(response.http_code, HttpCode::NotImplemented);
+        assert_eq!(content(&response), "Method BREW is not supported.");
+    }
+}
@@ -0,0 +1,33 @@
+use crate::middleware::{Middleware, Next};
+use crate::router::Router;
+use crate::{Cli, Request, Response};
+
+/// The router along with the middleware every request passes through on its way to it.
+pub struct Server {
+    router: Router,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Server {
+    pub fn new(router: Router) -> Self {
+        Self {
+            router,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Adds a middleware inside any added before it, so the first one added sees the
+    /// request first and the response last.
+    pub fn wrap(&mut self, middleware: impl Middleware + 'static) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    pub fn handle(&self, request: &mut Request, config: &Cli) -> Response {
+        Next {
+            middleware: &self.middleware,
+            router: &self.router,
+            config,
+        }
+        .run(request)
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+/// How often the accept loop looks up from waiting on new connections to check for a
+/// shutdown.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Set once SIGTERM or SIGINT arrives, after which the server stops accepting
+/// connections and finishes off the ones it already has.
+#[derive(Clone)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Starts listening for SIGTERM and SIGINT.
+    ///
+    /// A second signal while still draining exits straight away, for when waiting on
+    /// a slow client isn't worth it.
+    pub fn install() -> std::io::Result<Self> {
+        let flag = Arc::new(AtomicBool::new(false));
+        for signal in [SIGTERM, SIGINT] {
+            // Registered first, so it only sees the flag as it was before this signal.
+            signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&flag))?;
+            signal_hook::flag::register(signal, Arc::clone(&flag))?;
+        }
+        Ok(Self { flag })
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    /// Kills the process if the drain takes longer than `deadline`, cutting off
+    /// whichever connections are still open.
+    pub fn exit_after(deadline: Duration) {
+        std::thread::spawn(move || {
+            std::thread::sleep(deadline);
+            eprintln!(
+                "Connections still open after {}s of draining, exiting anyway.",
+                deadline.as_secs()
+            );
+            std::process::exit(1);
+        });
+    }
+}
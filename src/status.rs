@@ -0,0 +1,152 @@
+use thiserror::Error;
+
+/// Declares `HttpCode` from the status code registry, so each variant's number and
+/// reason phrase are written down exactly once.
+macro_rules! http_codes {
+    ($($variant:ident = $code:literal $reason:literal,)+) => {
+        /// A response status code.
+        ///
+        /// Every code in the IANA HTTP Status Code Registry has a variant with its
+        /// standard reason phrase, any other three digit code can be made with
+        /// `HttpCode::try_from`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum HttpCode {
+            $($variant,)+
+            /// A code with no registered meaning, sent without a reason phrase. Always
+            /// within `100..=999`, so it fits the status line.
+            Custom(u16),
+        }
+
+        impl HttpCode {
+            pub fn code(self) -> u16 {
+                match self {
+                    $(HttpCode::$variant => $code,)+
+                    HttpCode::Custom(code) => code,
+                }
+            }
+
+            pub fn reason(self) -> &'static str {
+                match self {
+                    $(HttpCode::$variant => $reason,)+
+                    HttpCode::Custom(_) => "",
+                }
+            }
+        }
+
+        impl TryFrom<u16> for HttpCode {
+            type Error = InvalidHttpCode;
+
+            fn try_from(code: u16) -> Result<Self, Self::Error> {
+                match code {
+                    $($code => Ok(HttpCode::$variant),)+
+                    100..=999 => Ok(HttpCode::Custom(code)),
+                    _ => Err(InvalidHttpCode(code)),
+                }
+            }
+        }
+    };
+}
+
+http_codes! {
+    Continue = 100 "Continue",
+    SwitchingProtocols = 101 "Switching Protocols",
+    Processing = 102 "Processing",
+    EarlyHints = 103 "Early Hints",
+
+    Ok = 200 "OK",
+    Created = 201 "Created",
+    Accepted = 202 "Accepted",
+    NonAuthoritativeInformation = 203 "Non-Authoritative Information",
+    NoContent = 204 "No Content",
+    ResetContent = 205 "Reset Content",
+    PartialContent = 206 "Partial Content",
+    MultiStatus = 207 "Multi-Status",
+    AlreadyReported = 208 "Already Reported",
+    ImUsed = 226 "IM Used",
+
+    MultipleChoices = 300 "Multiple Choices",
+    MovedPermanently = 301 "Moved Permanently",
+    Found = 302 "Found",
+    SeeOther = 303 "See Other",
+    NotModified = 304 "Not Modified",
+    UseProxy = 305 "Use Proxy",
+    TemporaryRedirect = 307 "Temporary Redirect",
+    PermanentRedirect = 308 "Permanent Redirect",
+
+    BadRequest = 400 "Bad Request",
+    Unauthorized = 401 "Unauthorized",
+    PaymentRequired = 402 "Payment Required",
+    Forbidden = 403 "Forbidden",
+    NotFound = 404 "Not Found",
+    MethodNotAllowed = 405 "Method Not Allowed",
+    NotAcceptable = 406 "Not Acceptable",
+    ProxyAuthenticationRequired = 407 "Proxy Authentication Required",
+    RequestTimeout = 408 "Request Timeout",
+    Conflict = 409 "Conflict",
+    Gone = 410 "Gone",
+    LengthRequired = 411 "Length Required",
+    PreconditionFailed = 412 "Precondition Failed",
+    ContentTooLarge = 413 "Content Too Large",
+    UriTooLong = 414 "URI Too Long",
+    UnsupportedMediaType = 415 "Unsupported Media Type",
+    RangeNotSatisfiable = 416 "Range Not Satisfiable",
+    ExpectationFailed = 417 "Expectation Failed",
+    MisdirectedRequest = 421 "Misdirected Request",
+    UnprocessableContent = 422 "Unprocessable Content",
+    Locked = 423 "Locked",
+    FailedDependency = 424 "Failed Dependency",
+    TooEarly = 425 "Too Early",
+    UpgradeRequired = 426 "Upgrade Required",
+    PreconditionRequired = 428 "Precondition Required",
+    TooManyRequests = 429 "Too Many Requests",
+    RequestHeaderFieldsTooLarge = 431 "Request Header Fields Too Large",
+    UnavailableForLegalReasons = 451 "Unavailable For Legal Reasons",
+
+    InternalServerError = 500 "Internal Server Error",
+    NotImplemented = 501 "Not Implemented",
+    BadGateway = 502 "Bad Gateway",
+    ServiceUnavailable = 503 "Service Unavailable",
+    GatewayTimeout = 504 "Gateway Timeout",
+    HttpVersionNotSupported = 505 "HTTP Version Not Supported",
+    VariantAlsoNegotiates = 506 "Variant Also Negotiates",
+    InsufficientStorage = 507 "Insufficient Storage",
+    LoopDetected = 508 "Loop Detected",
+    NotExtended = 510 "Not Extended",
+    NetworkAuthenticationRequired = 511 "Network Authentication Required",
+}
+
+/// A status line only has room for three digits.
+#[derive(Debug, Error)]
+#[error("{0} is not a three digit status code")]
+pub struct InvalidHttpCode(pub u16);
+
+impl HttpCode {
+    /// The code and reason phrase as they appear in the status line.
+    pub fn to_tcp_format(self) -> String {
+        debug_assert!((100..=999).contains(&self.code()), "Bad status {:?}", self);
+        format!("{} {}", self.code(), self.reason())
+    }
+}
+
+#[allow(dead_code)] // Not every class is checked for yet.
+impl HttpCode {
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    pub fn is_redirection(self) -> bool {
+        (300..400).contains(&self.code())
+    }
+
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.code())
+    }
+
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.code())
+    }
+}
@@ -0,0 +1,284 @@
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{HttpCode, HttpMethod, Request};
+
+/// What identifies a version of a file, so clients can tell whether the copy they
+/// already have is still current.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    /// The ETag, quotes and any `W/` included.
+    pub etag: String,
+    /// Truncated to whole seconds, since that's all an HTTP date can hold.
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    /// Derives the validators from a file's size and modification time, so they change
+    /// whenever the file is written.
+    ///
+    /// The ETag is weak if the file system only keeps whole seconds and the file is
+    /// less than a second old, as another write in the same second could leave both the
+    /// size and time as they are.
+    pub fn of(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let weak = since_epoch.subsec_nanos() == 0
+            && SystemTime::now()
+                .duration_since(modified)
+                .map_or(true, |age| age < Duration::from_secs(1));
+
+        Self {
+            etag: format!(
+                "{}\"{:x}.{:x}-{:x}\"",
+                if weak { "W/" } else { "" },
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos(),
+                metadata.len()
+            ),
+            last_modified: UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()),
+        }
+    }
+
+    pub fn last_modified_header(&self) -> String {
+        httpdate::fmt_http_date(self.last_modified)
+    }
+
+    /// Whether an `If-Range` value still matches, which needs a strong ETag or the exact
+    /// modification date.
+    pub fn if_range_matches(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            return strong_match(if_range, &self.etag);
+        }
+        httpdate::parse_http_date(if_range).is_ok_and(|date| date == self.last_modified)
+    }
+}
+
+/// Checks a request's `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` headers against the file as it is now, `None` if it doesn't
+/// exist.
+///
+/// Returns the status to answer with instead of carrying on, 304 Not Modified when a
+/// GET or HEAD would get back what the client already has, or 412 Precondition
+/// Failed. The headers are looked at in the order RFC 9110 gives, so a date is only
+/// used when there's no ETag condition to go on.
+pub fn preconditions(request: &Request, current: Option<&Validators>) -> Option<HttpCode> {
+    let headers = &request.headers;
+    let safe = matches!(request.method, HttpMethod::Get | HttpMethod::Head);
+
+    if let Some(if_match) = headers.get("If-Match") {
+        let matched = match current {
+            Some(current) => etag_list_matches(if_match, |tag| strong_match(tag, &current.etag)),
+            None => false,
+        };
+        if !matched {
+            return Some(HttpCode::PreconditionFailed);
+        }
+    } else if let (Some(since), Some(current)) = (headers.get("If-Unmodified-Since"), current) {
+        if httpdate::parse_http_date(since).is_ok_and(|since| current.last_modified > since) {
+            return Some(HttpCode::PreconditionFailed);
+        }
+    }
+
+    if let Some(if_none_match) = headers.get("If-None-Match") {
+        let matched = match current {
+            Some(current) => etag_list_matches(if_none_match, |tag| weak_match(tag, &current.etag)),
+            None => false,
+        };
+        if matched {
+            return Some(if safe {
+                HttpCode::NotModified
+            } else {
+                HttpCode::PreconditionFailed
+            });
+        }
+    } else if let (Some(since), Some(current), true) =
+        (headers.get("If-Modified-Since"), current, safe)
+    {
+        if httpdate::parse_http_date(since).is_ok_and(|since| current.last_modified <= since) {
+            return Some(HttpCode::NotModified);
+        }
+    }
+    None
+}
+
+/// Whether any entity tag in an `If-Match` or `If-None-Match` list matches, `*` matching
+/// anything that exists.
+///
+/// Tags are split by hand rather than with `Headers::get_list`, since a comma is
+/// allowed inside the quotes.
+fn etag_list_matches(list: &str, mut matches: impl FnMut(&str) -> bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return false;
+        }
+        let opening = if rest.starts_with("W/\"") { 3 } else { 1 };
+        if !rest[opening - 1..].starts_with('"') {
+            // Not a list of entity tags, so nothing in it can match.
+            return false;
+        }
+        let Some(closing) = rest[opening..].find('"') else {
+            return false;
+        };
+        let (tag, remaining) = rest.split_at(opening + closing + 1);
+        if matches(tag) {
+            return true;
+        }
+        rest = remaining;
+    }
+}
+
+/// The tags are the same and neither is weak.
+fn strong_match(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && a == b
+}
+
+/// The tags are the same once any `W/` is ignored.
+fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// The weak version of an ETag, for when the bytes sent differ from the file's, as
+/// they do when compressed.
+pub fn weaken(etag: &str) -> String {
+    if etag.starts_with("W/") {
+        etag.into()
+    } else {
+        format!("W/{}", etag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        let mut head = format!("{} /files/f HTTP/1.1", method);
+        for (name, value) in headers {
+            head += &format!("\r\n{}: {}", name, value);
+        }
+        Request::parse_head(head.as_bytes()).unwrap().0
+    }
+
+    fn current() -> Validators {
+        Validators {
+            etag: "\"abc\"".into(),
+            last_modified: UNIX_EPOCH + Duration::from_secs(1_000_000),
+        }
+    }
+
+    fn date(secs: u64) -> String {
+        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn splits_entity_tag_lists() {
+        let found = |list: &str| {
+            let mut tags = Vec::new();
+            etag_list_matches(list, |tag| {
+                tags.push(tag.to_string());
+                false
+            });
+            tags
+        };
+        assert_eq!(found(r#""a", "b""#), [r#""a""#, r#""b""#]);
+        // Commas are allowed inside the quotes.
+        assert_eq!(found(r#""a,b",W/"c, d""#), [r#""a,b""#, r#"W/"c, d""#]);
+        assert_eq!(found(r#" ,, "a" ,"#), [r#""a""#]);
+        // Anything that isn't a quoted tag ends the list.
+        assert_eq!(found(r#""a", b, "c""#), [r#""a""#]);
+        assert_eq!(found(r#""unterminated"#), Vec::<String>::new());
+        assert!(etag_list_matches(" * ", |_| false));
+    }
+
+    #[test]
+    fn compares_strongly_or_weakly() {
+        let current = current();
+        let check =
+            |method, name, value| preconditions(&request(method, &[(name, value)]), Some(&current));
+        assert_eq!(check("PUT", "If-Match", r#""abc""#), None);
+        assert_eq!(
+            check("PUT", "If-Match", r#"W/"abc""#),
+            Some(HttpCode::PreconditionFailed)
+        );
+        assert_eq!(
+            check("GET", "If-None-Match", r#""x", W/"abc""#),
+            Some(HttpCode::NotModified)
+        );
+        assert_eq!(
+            check("PUT", "If-None-Match", r#"W/"abc""#),
+            Some(HttpCode::PreconditionFailed)
+        );
+        assert_eq!(check("GET", "If-None-Match", r#""abcd""#), None);
+    }
+
+    #[test]
+    fn star_only_matches_a_file_that_exists() {
+        let current = current();
+        let star = |method, name| request(method, &[(name, "*")]);
+        assert_eq!(
+            preconditions(&star("PUT", "If-Match"), None),
+            Some(HttpCode::PreconditionFailed)
+        );
+        assert_eq!(
+            preconditions(&star("PUT", "If-Match"), Some(&current)),
+            None
+        );
+        assert_eq!(preconditions(&star("PUT", "If-None-Match"), None), None);
+        assert_eq!(
+            preconditions(&star("PUT", "If-None-Match"), Some(&current)),
+            Some(HttpCode::PreconditionFailed)
+        );
+        assert_eq!(
+            preconditions(&star("GET", "If-None-Match"), Some(&current)),
+            Some(HttpCode::NotModified)
+        );
+    }
+
+    #[test]
+    fn entity_tags_take_precedence_over_dates() {
+        let current = current();
+        let check = |method, headers: &[(&str, &str)]| {
+            preconditions(&request(method, headers), Some(&current))
+        };
+        let before = date(999_999);
+        let after = date(1_000_001);
+
+        assert_eq!(
+            check("PUT", &[("If-Unmodified-Since", &before)]),
+            Some(HttpCode::PreconditionFailed)
+        );
+        assert_eq!(check("PUT", &[("If-Unmodified-Since", &after)]), None);
+        // A matching If-Match means If-Unmodified-Since isn't looked at.
+        assert_eq!(
+            check(
+                "PUT",
+                &[("If-Match", r#""abc""#), ("If-Unmodified-Since", &before)]
+            ),
+            None
+        );
+
+        assert_eq!(
+            check("GET", &[("If-Modified-Since", &after)]),
+            Some(HttpCode::NotModified)
+        );
+        assert_eq!(check("GET", &[("If-Modified-Since", &before)]), None);
+        assert_eq!(check("PUT", &[("If-Modified-Since", &after)]), None);
+        // Likewise If-None-Match overrides If-Modified-Since.
+        assert_eq!(
+            check(
+                "GET",
+                &[("If-None-Match", r#""old""#), ("If-Modified-Since", &after)]
+            ),
+            None
+        );
+        // Dates that don't parse are ignored.
+        assert_eq!(check("GET", &[("If-Modified-Since", "yesterday")]), None);
+    }
+}
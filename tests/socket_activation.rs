@@ -0,0 +1,118 @@
+//! Starts the server the way a supervisor using systemd's socket activation would,
+//! with the listening socket already open on file descriptor 3.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// Runs the server with `listener` as fd 3. `LISTEN_PID` is left for the shell to fill
+/// in with its own pid, which the server keeps once the shell execs it, unless
+/// `listen_pid` is given.
+fn spawn(listener: &TcpListener, listen_pid: Option<&str>, args: &[&str]) -> Child {
+    let fd = listener.as_raw_fd();
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(r#"LISTEN_PID=${LISTEN_PID:-$$} exec "$0" "$@""#)
+        .arg(env!("CARGO_BIN_EXE_codecrafters-http-server"))
+        .arg("--directory")
+        .arg(std::env::temp_dir())
+        .args(args)
+        .env("LISTEN_FDS", "1")
+        .env_remove("LISTEN_PID")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    if let Some(pid) = listen_pid {
+        command.env("LISTEN_PID", pid);
+    }
+    // SAFETY: only async-signal-safe calls between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 leaves the copy open across exec, but does nothing if it's already
+            // on 3, so that case has to be done by hand.
+            let result = if fd == 3 {
+                libc::fcntl(3, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if result == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn().unwrap()
+}
+
+/// Sends a request for `/echo/{word}`, retrying while the server starts up.
+fn echo(address: SocketAddr, word: &str) -> String {
+    let mut stream = (0..50)
+        .find_map(|_| {
+            TcpStream::connect(address)
+                .map_err(|_| std::thread::sleep(Duration::from_millis(100)))
+                .ok()
+        })
+        .expect("Server never started listening");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /echo/{} HTTP/1.1\r\nConnection: close\r\n\r\n",
+        word
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn stop(mut server: Child) -> String {
+    server.kill().unwrap();
+    let output = server.wait_with_output().unwrap();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn serves_on_an_inherited_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = spawn(&listener, None, &[]);
+    // Once it's been handed on, only the server should be accepting.
+    drop(listener);
+
+    let response = echo(address, "inherited");
+    let log = stop(server);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\ninherited"), "{}", response);
+    assert!(
+        log.contains(&format!("Listening on inherited socket {}", address)),
+        "{}",
+        log
+    );
+}
+
+#[test]
+fn ignores_sockets_meant_for_another_process() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // A free port for the server to fall back to binding itself.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let server = spawn(&listener, Some("1"), &["--port", &port.to_string()]);
+
+    let response = echo(SocketAddr::from(([127, 0, 0, 1], port)), "fallback");
+    let log = stop(server);
+    assert!(response.ends_with("\r\n\r\nfallback"), "{}", response);
+    assert!(
+        log.contains("Ignoring sockets passed on to process 1"),
+        "{}",
+        log
+    );
+    assert!(!log.contains("inherited socket"), "{}", log);
+}